@@ -1,17 +1,140 @@
+// How many of a pass's characters pick the row (F/B) and then the column
+// (L/R). The puzzle's plane has 128 rows of 8 seats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Layout {
+    row_bits: usize,
+    col_bits: usize,
+}
 
-pub fn read_line(line: &str) -> usize {
-    let bytes = line.bytes();
-    assert_eq!(bytes.len(), 10);
+impl Layout {
+    pub const STANDARD: Layout = Layout { row_bits: 7, col_bits: 3 };
+
+    // None if seat ids wouldn't fit in a usize
+    pub fn new(row_bits: usize, col_bits: usize) -> Option<Self> {
+        if row_bits + col_bits >= usize::BITS as usize {
+            return None;
+        }
+        Some(Self { row_bits, col_bits })
+    }
+
+    pub fn rows(&self) -> usize {
+        1 << self.row_bits
+    }
+
+    pub fn cols(&self) -> usize {
+        1 << self.col_bits
+    }
+
+    pub fn code_len(&self) -> usize {
+        self.row_bits + self.col_bits
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::STANDARD
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Length { expected: usize, found: usize },
+    // Not the character for this part of the code, e.g. an L among the rows
+    BadChar { pos: usize, found: char },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BoardingPass {
+    layout: Layout,
+    row: usize,
+    col: usize,
+}
+
+impl BoardingPass {
+    // None if the seat isn't on the plane
+    pub fn new(layout: Layout, row: usize, col: usize) -> Option<Self> {
+        if row >= layout.rows() || col >= layout.cols() {
+            return None;
+        }
+        Some(Self { layout, row, col })
+    }
+
+    pub fn from_id(layout: Layout, id: usize) -> Option<Self> {
+        Self::new(layout, id >> layout.col_bits, id & (layout.cols() - 1))
+    }
+
+    pub fn decode(code: &str) -> Result<Self, DecodeError> {
+        Self::decode_with(code, Layout::STANDARD)
+    }
+
+    // F/B for each row bit, then L/R for each column bit, high bits first
+    pub fn decode_with(code: &str, layout: Layout) -> Result<Self, DecodeError> {
+        let found = code.chars().count();
+        if found != layout.code_len() {
+            return Err(DecodeError::Length { expected: layout.code_len(), found });
+        }
+
+        let mut row = 0;
+        let mut col = 0;
+        for (pos, c) in code.chars().enumerate() {
+            let (acc, bit) = match (pos < layout.row_bits, c) {
+                (true, 'F') => (&mut row, 0),
+                (true, 'B') => (&mut row, 1),
+                (false, 'L') => (&mut col, 0),
+                (false, 'R') => (&mut col, 1),
+                _ => return Err(DecodeError::BadChar { pos, found: c }),
+            };
+            *acc = (*acc << 1) | bit;
+        }
+        Ok(Self { layout, row, col })
+    }
+
+    pub fn encode(&self) -> String {
+        let bits = |val: usize, width: usize, zero: char, one: char| {
+            (0..width).rev().map(move |i| if val >> i & 1 == 1 { one } else { zero })
+        };
+        bits(self.row, self.layout.row_bits, 'F', 'B')
+            .chain(bits(self.col, self.layout.col_bits, 'L', 'R'))
+            .collect()
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn row(&self) -> usize {
+        self.row
+    }
 
-    let mut val: usize = 0;
-    for i in bytes {
-        val <<= 1;
-        if i == 'R' as u8 || i == 'B' as u8 {
-            val += 1;
-        } 
+    pub fn col(&self) -> usize {
+        self.col
     }
 
-    val
+    pub fn id(&self) -> usize {
+        (self.row << self.layout.col_bits) | self.col
+    }
+}
+
+impl std::fmt::Display for BoardingPass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.encode())
+    }
+}
+
+// Runs of ids missing between the lowest and highest of ids, in order, as
+// (first, last) of each run
+pub fn find_gaps(ids: &[usize]) -> Vec<(usize, usize)> {
+    let mut sorted = ids.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    sorted.windows(2)
+        .filter(|pair| pair[1] - pair[0] > 1)
+        .map(|pair| (pair[0] + 1, pair[1] - 1))
+        .collect()
+}
+
+pub fn read_line(line: &str) -> usize {
+    BoardingPass::decode(line).unwrap().id()
 }
 
 #[cfg(test)]
@@ -38,6 +161,47 @@ mod tests {
 
         println!("Max val: {}", max);
         println!("Your seat: {}", down);
+        let ids: Vec<usize> = file.lines().map(read_line).collect();
+        assert_eq!(find_gaps(&ids).last().map(|(_, last)| *last), Some(down));
+    }
+
+    #[test]
+    fn codec() {
+        for (code, row, col, id) in [
+            ("FBFBBFFRLR", 44, 5, 357),
+            ("BFFFBBFRRR", 70, 7, 567),
+            ("FFFBBBFRRR", 14, 7, 119),
+            ("BBFFBBFRLL", 102, 4, 820),
+        ].iter() {
+            let pass = BoardingPass::decode(code).unwrap();
+            assert_eq!((pass.row(), pass.col(), pass.id()), (*row, *col, *id));
+            assert_eq!(pass.to_string(), *code);
+            assert_eq!(BoardingPass::from_id(Layout::STANDARD, *id), Some(pass));
+        }
+        for id in 0..1024 {
+            let pass = BoardingPass::from_id(Layout::default(), id).unwrap();
+            assert_eq!(BoardingPass::decode(&pass.encode()), Ok(pass));
+        }
+        assert_eq!(BoardingPass::from_id(Layout::STANDARD, 1024), None);
+        assert_eq!(BoardingPass::new(Layout::STANDARD, 127, 8), None);
+
+        assert_eq!(BoardingPass::decode("FBFBBFFRL"), Err(DecodeError::Length { expected: 10, found: 9 }));
+        assert_eq!(BoardingPass::decode("FBFBBFFRLF"), Err(DecodeError::BadChar { pos: 9, found: 'F' }));
+        assert_eq!(BoardingPass::decode("RBFBBFFRLR"), Err(DecodeError::BadChar { pos: 0, found: 'R' }));
+        assert_eq!(BoardingPass::decode("FBFBBFxRLR"), Err(DecodeError::BadChar { pos: 6, found: 'x' }));
+
+        // A small plane of 4 rows of 2 seats
+        let small = Layout::new(2, 1).unwrap();
+        let pass = BoardingPass::decode_with("BFR", small).unwrap();
+        assert_eq!((pass.row(), pass.col(), pass.id()), (2, 1, 5));
+        assert_eq!(BoardingPass::new(small, 3, 0).unwrap().encode(), "BBL");
+        assert!(BoardingPass::decode_with("FBFBBFFRLR", small).is_err());
+        assert!(Layout::new(40, 30).is_none());
+
+        assert_eq!(find_gaps(&[7, 1, 4, 2, 4]), vec![(3, 3), (5, 6)]);
+        assert_eq!(find_gaps(&[5, 6]), vec![]);
+        assert_eq!(find_gaps(&[]), vec![]);
+        assert_eq!(find_gaps(&[1 << 40, 0]), vec![(1, (1 << 40) - 1)]);
     }
 
 
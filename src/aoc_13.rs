@@ -1,3 +1,83 @@
+use crate::crt::{self, CrtError};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// The puzzle input: the earliest time we can leave, and the buses in
+// service as (offset in the list, bus id), with the x's left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    pub earliest: usize,
+    pub buses: Vec<(usize, usize)>,
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<Self, ()> {
+        let mut lines = input.lines();
+        let earliest = lines.next().ok_or(())?.trim().parse::<usize>().map_err(|_| ())?;
+
+        let mut buses = vec![];
+        for (offset, s) in lines.next().ok_or(())?.trim().split(',').enumerate() {
+            if s == "x" {
+                continue;
+            }
+            match s.parse::<usize>() {
+                Ok(id) if id > 0 => buses.push((offset, id)),
+                _ => return Err(()),
+            }
+        }
+        Ok(Self { earliest, buses })
+    }
+}
+
+impl Schedule {
+    pub fn ids(&self) -> Vec<usize> {
+        self.buses.iter().map(|(_, id)| *id).collect()
+    }
+
+    // The next k departures at or after t, as (time, bus id), soonest first.
+    // Unlike do_it, a bus leaving right at t counts. A bus drops out once
+    // its next time would overflow, so there may be fewer than k.
+    pub fn next_departures(&self, t: usize, k: usize) -> Vec<(usize, usize)> {
+        let mut heap: BinaryHeap<Reverse<(usize, usize)>> = self.buses.iter()
+            .filter_map(|(_, id)| Some(Reverse((t.div_ceil(*id).checked_mul(*id)?, *id))))
+            .collect();
+        let mut out = vec![];
+        while out.len() < k {
+            let Some(Reverse((time, id))) = heap.pop() else { break };
+            out.push((time, id));
+            if let Some(next) = time.checked_add(id) {
+                heap.push(Reverse((next, id)));
+            }
+        }
+        out
+    }
+
+    // Every t where each bus leaves its offset after t is first + n * period
+    fn alignment(&self) -> Result<crt::Solution<i128>, CrtError> {
+        let congruences: Vec<(i128, i128)> = self.buses.iter()
+            .map(|(offset, id)| (-(*offset as i128), *id as i128))
+            .collect();
+        crt::solve(&congruences)
+    }
+
+    // Part 2, which fails if bus ids sharing factors make it impossible
+    pub fn first_aligned_departure(&self) -> Result<i128, CrtError> {
+        Ok(self.alignment()?.value)
+    }
+
+    // The first alignment strictly after t
+    pub fn alignment_after(&self, t: i128) -> Result<i128, CrtError> {
+        let crt::Solution { value, period } = self.alignment()?;
+        if t < value {
+            return Ok(value);
+        }
+        let periods = (t - value) / period + 1;
+        periods.checked_mul(period).and_then(|x| x.checked_add(value)).ok_or(CrtError::Overflow)
+    }
+}
+
 pub fn do_it(target: usize, nums: &Vec<usize>) -> (usize, usize) {
     let mut min_train_id = 0;
     let mut min_train_time = usize::MAX;
@@ -77,31 +157,56 @@ mod tests {
 
     fn drive(filename: &str) {
         let input = std::fs::read_to_string(filename).unwrap();
-        let mut lines = input.lines();
-        let target = lines.next().unwrap().parse::<usize>().unwrap();
+        let schedule = input.parse::<Schedule>().unwrap();
+        let target = schedule.earliest;
 
-        let trains: Vec<usize> = lines.next().unwrap()
-            .split(",").filter_map(|s| s.parse::<usize>().ok())
-            .collect();
-
-        let (final_time, train_id) = do_it(target, &trains);
+        let (final_time, train_id) = do_it(target, &schedule.ids());
         println!("{} {} ==> {}", final_time, train_id, (final_time - target) * train_id);
     }
 
     fn drive_2(filename: &str) {
         let input = std::fs::read_to_string(filename).unwrap();
-        let mut lines = input.lines();
-        lines.next();
+        let schedule = input.parse::<Schedule>().unwrap();
 
-        let trains: Vec<(usize, usize)> = lines.next().unwrap()
-            .split(",").enumerate().filter_map(|(i, s)| {
-                s.parse::<usize>().ok().map(|x| (x, i))
-            }).collect();
+        let trains: Vec<(usize, usize)> = schedule.buses.iter().map(|(i, x)| (*x, *i)).collect();
 
         let acc = do_it_2(&trains);
+        assert_eq!(schedule.first_aligned_departure(), Ok(acc as i128));
         println!("{}", acc);
     }
 
+    #[test]
+    fn schedule() {
+        let schedule = "939\n7,13,x,x,59,x,31,19".parse::<Schedule>().unwrap();
+        assert_eq!(schedule.buses, vec![(0, 7), (1, 13), (4, 59), (6, 31), (7, 19)]);
+        assert_eq!(schedule.next_departures(939, 4), vec![(944, 59), (945, 7), (949, 13), (950, 19)]);
+        assert_eq!(schedule.next_departures(0, 2), vec![(0, 7), (0, 13)]);
+
+        // A bus leaving right at t is the next departure, where do_it
+        // only looks strictly after t
+        assert_eq!(schedule.next_departures(945, 2), vec![(945, 7), (949, 13)]);
+        assert_eq!(do_it(945, &schedule.ids()), (949, 13));
+        assert_eq!(schedule.next_departures(944, 1), vec![do_it(943, &schedule.ids())]);
+        // Near the top only the departures that fit a usize come out
+        assert_eq!(schedule.next_departures(usize::MAX - 1, 5), vec![(usize::MAX - 1, 7)]);
+        assert_eq!(schedule.next_departures(usize::MAX - 20, 10).len(), 8);
+        assert_eq!(schedule.first_aligned_departure(), Ok(1068781));
+        let period = 7 * 13 * 59 * 31 * 19;
+        assert_eq!(schedule.alignment_after(0), Ok(1068781));
+        assert_eq!(schedule.alignment_after(1068781), Ok(1068781 + period));
+        assert_eq!(schedule.alignment_after(1068781 + period - 1), Ok(1068781 + period));
+
+        // Shared factors: 4 and 6 can't leave one minute apart
+        let schedule = "0\n4,6".parse::<Schedule>().unwrap();
+        assert_eq!(schedule.first_aligned_departure(), Err(CrtError::Inconsistent(1)));
+        let schedule = "0\n4,x,6".parse::<Schedule>().unwrap();
+        assert_eq!(schedule.first_aligned_departure(), Ok(4));
+
+        for bad in ["", "939", "939\n7,y", "939\n7,0", "t\n7"].iter() {
+            assert!(bad.parse::<Schedule>().is_err(), "{:?}", bad);
+        }
+    }
+
     #[test]
     fn it_works() {
         drive("res/13/input_simple.txt");
@@ -2,41 +2,195 @@ use regex::Regex;
 use lazy_static::lazy_static;
 use std::collections::{HashMap};
 
+// Masks can be up to 128 bits wide
 #[derive(Debug)]
 pub enum Command {
-    Mask ( usize, usize, usize ),
-    Mem ( usize, usize,)
+    Mask ( u128, u128, u128 ),
+    Mem ( u128, u128,)
 }
 
 pub trait Computer {
     fn new() -> Self;
     fn exec(&mut self, cmd: &Command);
-    fn sum(&self) -> usize;
+    // None if the total doesn't fit a u128
+    fn sum(&self) -> Option<u128>;
+    // Start recording a TraceEntry for every instruction run from now on
+    fn enable_trace(&mut self);
+    fn trace(&self) -> &[TraceEntry];
+    // Everything in memory, sorted by address
+    fn memory_snapshot(&self) -> Vec<(AddressPattern, u128)>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Mask {
+    pub set_bits: u128,
+    pub clear_bits: u128,
+    pub x_bits: u128,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub step: usize,
+    // Mask in effect after the instruction ran
+    pub mask: Mask,
+    // Addresses touched and the value written there, for mem instructions
+    pub write: Option<(AddressPattern, u128)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryDiff {
+    OnlyLeft(AddressPattern, u128),
+    OnlyRight(AddressPattern, u128),
+    Changed(AddressPattern, u128, u128),
 }
 
 #[derive(Debug)]
 pub struct Computer1 {
-    mem: HashMap<usize, usize>,
-    set_bits: usize,
-    clear_bits: usize,
+    mem: HashMap<u128, u128>,
+    mask: Mask,
+    steps: usize,
+    trace: Option<Vec<TraceEntry>>,
 }
 
 #[derive(Debug)]
 pub struct Computer2 {
-    // addr, x-mask, val
-    mem: HashMap<usize, usize>,
-    set_bits: usize,
-    clear_bits: usize,
-    x_bits: usize,
+    // Disjoint address patterns and the value stored at every address in them
+    mem: Vec<(AddressPattern, u128)>,
+    mask: Mask,
+    steps: usize,
+    trace: Option<Vec<TraceEntry>>,
+}
+
+// A set of addresses: fixed bits that must match, and floating bits that
+// can be anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AddressPattern {
+    fixed: u128,
+    floating: u128,
+}
+
+fn bit_width(bits: u128) -> usize {
+    (128 - bits.leading_zeros() as usize).max(1)
+}
+
+impl Mask {
+    pub fn width(&self) -> usize {
+        bit_width(self.set_bits | self.clear_bits | self.x_bits)
+    }
+}
+
+impl std::fmt::Display for Mask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for i in (0..self.width()).rev() {
+            let bit = 1 << i;
+            if self.set_bits & bit != 0 {
+                write!(f, "1")?;
+            } else if self.clear_bits & bit != 0 {
+                write!(f, "0")?;
+            } else {
+                write!(f, "X")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for AddressPattern {
+    // Pads out to the formatter width, e.g. {:36}
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = bit_width(self.fixed | self.floating).max(f.width().unwrap_or(0));
+        for i in (0..width).rev() {
+            let bit = 1 << i;
+            if self.floating & bit != 0 {
+                write!(f, "X")?;
+            } else if self.fixed & bit != 0 {
+                write!(f, "1")?;
+            } else {
+                write!(f, "0")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.write {
+            Some((pattern, val)) => {
+                let width = self.mask.width();
+                write!(f, "{:>4}: mem[{:width$}] = {}", self.step, pattern, val, width = width)
+            },
+            None => write!(f, "{:>4}: mask = {}", self.step, self.mask),
+        }
+    }
+}
+
+impl AddressPattern {
+    pub fn new(addr: u128, floating: u128) -> Self {
+        Self { fixed: addr & !floating, floating }
+    }
+
+    pub fn contains(&self, addr: u128) -> bool {
+        addr & !self.floating == self.fixed
+    }
+
+    // How many addresses the pattern covers, None if it doesn't fit
+    pub fn size(&self) -> Option<u128> {
+        1u128.checked_shl(self.floating.count_ones())
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = u128> {
+        let fixed = self.fixed;
+        Twister::new(self.floating).map(move |t| fixed | t)
+    }
+
+    fn intersects(&self, other: &Self) -> bool {
+        let both_fixed = !self.floating & !other.floating;
+        (self.fixed ^ other.fixed) & both_fixed == 0
+    }
+
+    fn intersection(&self, other: &Self) -> Option<Self> {
+        if self.intersects(other) {
+            Some(Self { fixed: self.fixed | other.fixed, floating: self.floating & other.floating })
+        } else {
+            None
+        }
+    }
+
+    // Splits self into disjoint patterns covering everything not in other.
+    // Each bit floating in self but fixed in other gives one piece, where that
+    // bit disagrees with other and the bits before it agree.
+    fn subtract(&self, other: &Self) -> Vec<Self> {
+        if !self.intersects(other) {
+            return vec![*self];
+        }
+
+        let mut pieces = vec![];
+        let mut current = *self;
+        let mut split_bits = self.floating & !other.floating;
+        while split_bits != 0 {
+            let bit = split_bits & split_bits.wrapping_neg();
+            split_bits &= !bit;
+
+            current.floating &= !bit;
+            pieces.push(Self {
+                fixed: current.fixed | (!other.fixed & bit),
+                floating: current.floating,
+            });
+            current.fixed |= other.fixed & bit;
+        }
+
+        pieces
+    }
 }
 
 struct Twister {
-    current: usize,
+    current: u128,
     mask_bits: Vec<usize>,
 }
 
 impl Twister {
-    fn new(mut mask: usize) -> Self {
+    fn new(mut mask: u128) -> Self {
         let mut mask_bits = vec![];
         let mut i = 0;
         while mask > 0 {
@@ -55,11 +209,11 @@ impl Twister {
 }
 
 impl Iterator for Twister {
-    type Item = usize;
+    type Item = u128;
 
-    fn next(&mut self) -> Option<usize> {
-        if self.current & (1 << self.mask_bits.len()) != 0 {
-            return None;    
+    fn next(&mut self) -> Option<u128> {
+        if self.mask_bits.len() < 128 && self.current & (1 << self.mask_bits.len()) != 0 {
+            return None;
         }
 
         let mut acc = 0;
@@ -106,7 +260,7 @@ impl Command {
 
     pub fn from_line(line: &str) -> Option<Self> {
         lazy_static! {
-            static ref MASK_RE: Regex = Regex::new(r"^mask = ([X01]{36})$").unwrap();
+            static ref MASK_RE: Regex = Regex::new(r"^mask = ([X01]{1,128})$").unwrap();
             static ref MEM_RE: Regex = Regex::new(r"^mem\[(\d+)\] = (\d+)$").unwrap();
         }
 
@@ -115,8 +269,8 @@ impl Command {
         } else if let Some(caps) = MEM_RE.captures(line) {
             Some(
                 Self::Mem(
-                    caps[1].parse::<usize>().unwrap(),
-                    caps[2].parse::<usize>().unwrap(),
+                    caps[1].parse::<u128>().ok()?,
+                    caps[2].parse::<u128>().ok()?,
                 )
             )    
         } else {
@@ -125,67 +279,145 @@ impl Command {
     }
 }
 
+fn record(trace: &mut Option<Vec<TraceEntry>>, step: usize, mask: Mask, write: Option<(AddressPattern, u128)>) {
+    if let Some(trace) = trace {
+        trace.push(TraceEntry { step, mask, write });
+    }
+}
+
 impl Computer for Computer1 {
     fn new() -> Self {
         Self {
             mem: HashMap::new(),
-            set_bits: 0,
-            clear_bits: 0,
+            mask: Mask::default(),
+            steps: 0,
+            trace: None,
         }
     }
 
     fn exec(&mut self, cmd: &Command) {
-        match cmd {
-            Command::Mask(set_bits, clear_bits, _) => {
-                self.set_bits = *set_bits;
-                self.clear_bits = *clear_bits;
+        let write = match cmd {
+            Command::Mask(set_bits, clear_bits, x_bits) => {
+                self.mask = Mask { set_bits: *set_bits, clear_bits: *clear_bits, x_bits: *x_bits };
+                None
             },
             Command::Mem(addr, val) => {
-                let val = val | self.set_bits;
-                let val = val & (!self.clear_bits);
+                let val = val | self.mask.set_bits;
+                let val = val & (!self.mask.clear_bits);
                 self.mem.insert(*addr, val);
+                Some((AddressPattern::new(*addr, 0), val))
             }
-        }
+        };
+        record(&mut self.trace, self.steps, self.mask, write);
+        self.steps += 1;
     }
 
-    fn sum(&self) -> usize {
-        self.mem.iter().fold(0, |acc, (_, val)| acc + val)
+    fn sum(&self) -> Option<u128> {
+        self.mem.iter().try_fold(0u128, |acc, (_, val)| acc.checked_add(*val))
+    }
+
+    fn enable_trace(&mut self) {
+        self.trace.get_or_insert_with(Vec::new);
+    }
+
+    fn trace(&self) -> &[TraceEntry] {
+        self.trace.as_deref().unwrap_or(&[])
+    }
+
+    fn memory_snapshot(&self) -> Vec<(AddressPattern, u128)> {
+        let mut snapshot = self.mem.iter()
+            .map(|(addr, val)| (AddressPattern::new(*addr, 0), *val))
+            .collect::<Vec<(AddressPattern, u128)>>();
+        snapshot.sort_unstable();
+        snapshot
     }
 }
 
 impl Computer for Computer2 {
     fn new() -> Self {
         Self {
-            mem: HashMap::new(),
-            set_bits: 0,
-            clear_bits: 0,
-            x_bits: 0,
+            mem: vec![],
+            mask: Mask::default(),
+            steps: 0,
+            trace: None,
         }
     }
 
     fn exec(&mut self, cmd: &Command) {
-        match cmd {
+        let write = match cmd {
             Command::Mask(set_bits, clear_bits, x_bits) => {
-                self.set_bits = *set_bits;
-                self.clear_bits = *clear_bits;
-                self.x_bits = *x_bits;
+                self.mask = Mask { set_bits: *set_bits, clear_bits: *clear_bits, x_bits: *x_bits };
+                None
             },
             Command::Mem(addr, val) => {
-                // println!("{:08b} {:08b}", *addr, self.set_bits);
-                let mut addr = *addr | self.set_bits;
-                addr &= !self.x_bits;
+                let written = AddressPattern::new(*addr | self.mask.set_bits, self.mask.x_bits);
 
-                for t in Twister::new(self.x_bits) {
-                    // println!("Tiwsting {:08b} {:08b} {:08b} {:08b}", self.x_bits, t, addr, addr | t);
-                    self.mem.insert(addr | t, *val);
+                // Carve the new write out of everything already stored, so
+                // the patterns stay disjoint without expanding them.
+                let mut mem = Vec::with_capacity(self.mem.len() + 1);
+                for (pattern, old_val) in self.mem.drain(..) {
+                    mem.extend(pattern.subtract(&written).into_iter().map(|p| (p, old_val)));
                 }
+                mem.push((written, *val));
+                self.mem = mem;
+                Some((written, *val))
             }
-        }
+        };
+        record(&mut self.trace, self.steps, self.mask, write);
+        self.steps += 1;
+    }
+
+    fn sum(&self) -> Option<u128> {
+        self.mem.iter().try_fold(0u128, |acc, (pattern, val)| {
+            pattern.size()?.checked_mul(*val)?.checked_add(acc)
+        })
+    }
+
+    fn enable_trace(&mut self) {
+        self.trace.get_or_insert_with(Vec::new);
+    }
+
+    fn trace(&self) -> &[TraceEntry] {
+        self.trace.as_deref().unwrap_or(&[])
+    }
+
+    fn memory_snapshot(&self) -> Vec<(AddressPattern, u128)> {
+        let mut snapshot = self.mem.clone();
+        snapshot.sort_unstable();
+        snapshot
     }
+}
+
+fn subtract_all(pattern: &AddressPattern, others: &[(AddressPattern, u128)]) -> Vec<AddressPattern> {
+    others.iter().fold(vec![*pattern], |pieces, (other, _)| {
+        pieces.iter().flat_map(|p| p.subtract(other)).collect()
+    })
+}
 
-    fn sum(&self) -> usize {
-        self.mem.iter().fold(0, |acc, (_, val)| acc + val)
+// Compares memory of two computers, typically one of each kind run over the
+// same program. Addresses are compared symbolically so floating writes are
+// never expanded.
+pub fn diff_memory<L: Computer, R: Computer>(left: &L, right: &R) -> Vec<MemoryDiff> {
+    let left = left.memory_snapshot();
+    let right = right.memory_snapshot();
+
+    let mut diffs = vec![];
+    for (l, l_val) in left.iter() {
+        for (r, r_val) in right.iter() {
+            if l_val == r_val {
+                continue;
+            }
+            if let Some(both) = l.intersection(r) {
+                diffs.push(MemoryDiff::Changed(both, *l_val, *r_val));
+            }
+        }
+        diffs.extend(subtract_all(l, &right).into_iter().map(|p| MemoryDiff::OnlyLeft(p, *l_val)));
+    }
+    for (r, r_val) in right.iter() {
+        diffs.extend(subtract_all(r, &left).into_iter().map(|p| MemoryDiff::OnlyRight(p, *r_val)));
     }
+
+    diffs
 }
 
 #[cfg(test)]
@@ -200,22 +432,139 @@ mod tests {
             puter.exec(&Command::from_line(line).unwrap());
         }
 
-        println!("{}", puter.sum());
+        println!("{}", puter.sum().unwrap());
     }
 
     #[test]
     fn twister_works() {
         assert_eq!(
             vec![ 0b0000, 0b0001, 0b0100, 0b0101 ],
-            Twister::new(0b0101).collect::<Vec<usize>>(),
+            Twister::new(0b0101).collect::<Vec<u128>>(),
         );
         assert_eq!(
             vec![ 0b000000, 0b000001, 0b000100, 0b000101,
                   0b100000, 0b100001, 0b100100, 0b100101,  ],
-            Twister::new(0b100101).collect::<Vec<usize>>(),
+            Twister::new(0b100101).collect::<Vec<u128>>(),
         );
     }
 
+    #[test]
+    fn subtract_patterns() {
+        let all = AddressPattern::new(0, 0b1111);
+        let hole = AddressPattern::new(0b0100, 0b1001);
+        let pieces = all.subtract(&hole);
+        assert_eq!(pieces.iter().map(|p| p.size().unwrap()).sum::<u128>(), 16 - 4);
+        for addr in 0..16 {
+            let count = pieces.iter().filter(|p| p.contains(addr)).count();
+            assert_eq!(count, if hole.contains(addr) { 0 } else { 1 });
+        }
+
+        let mut addrs = pieces.iter().flat_map(|p| p.addresses()).collect::<Vec<u128>>();
+        addrs.sort_unstable();
+        assert_eq!(addrs, vec![0b0000, 0b0001, 0b0010, 0b0011, 0b0110, 0b0111, 0b1000, 0b1001, 0b1010, 0b1011, 0b1110, 0b1111]);
+
+        // Disjoint patterns come back untouched
+        assert_eq!(AddressPattern::new(0b01, 0b10).subtract(&AddressPattern::new(0b00, 0b10)), vec![AddressPattern::new(0b01, 0b10)]);
+    }
+
+    #[test]
+    fn tracing() {
+        let input = std::fs::read_to_string("res/14/input_trival.txt").unwrap();
+        let program = input.lines().map(|line| Command::from_line(line).unwrap()).collect::<Vec<Command>>();
+
+        let mut puter_1 = Computer1::new();
+        let mut puter_2 = Computer2::new();
+        puter_1.enable_trace();
+        puter_2.enable_trace();
+        for cmd in program.iter() {
+            puter_1.exec(cmd);
+            puter_2.exec(cmd);
+        }
+
+        for entry in puter_2.trace() {
+            println!("{}", entry);
+        }
+        assert_eq!(puter_2.trace().len(), 4);
+        assert_eq!(puter_2.trace()[0].mask.to_string(), format!("{}X1001X", "0".repeat(30)));
+        assert_eq!(puter_2.trace()[1].to_string(), format!("   1: mem[{}X1101X] = 100", "0".repeat(30)));
+        assert_eq!(puter_2.trace()[3].write, Some((AddressPattern::new(0b10000, 0b1011), 1)));
+        assert_eq!(puter_1.trace()[1].write, Some((AddressPattern::new(42, 0), 0b110010)));
+
+        let snapshot = puter_2.memory_snapshot();
+        assert!(snapshot.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(snapshot.iter().map(|(p, _)| p.size().unwrap()).sum::<u128>(), 10);
+
+        // Part 1 writes 42 and 26, part 2 floats over 10 addresses including
+        // 26, where both end up holding 1.
+        let diffs = diff_memory(&puter_1, &puter_2);
+        let only_left = diffs.iter().filter(|d| matches!(d, MemoryDiff::OnlyLeft(..))).count();
+        let only_right = diffs.iter().filter_map(|d| match d {
+            MemoryDiff::OnlyRight(p, _) => p.size(),
+            _ => None,
+        }).sum::<u128>();
+        assert_eq!(only_left, 1);
+        assert_eq!(only_right, 9);
+        assert!(diff_memory(&puter_2, &puter_2).is_empty());
+
+        let mut other = Computer1::new();
+        other.exec(&program[2]);
+        other.exec(&Command::Mem(26, 7));
+        assert_eq!(diff_memory(&puter_1, &other), vec![
+            MemoryDiff::Changed(AddressPattern::new(26, 0), 1, 3),
+            MemoryDiff::OnlyLeft(AddressPattern::new(42, 0), 50),
+        ]);
+
+        let mut untraced = Computer1::new();
+        untraced.exec(&program[0]);
+        assert!(untraced.trace().is_empty());
+    }
+
+    #[test]
+    fn wide_masks() {
+        // 40 floating bits would be a trillion addresses written out
+        let mut puter = Computer2::new();
+        let program = [
+            format!("mask = {}{}", "0".repeat(60), "X".repeat(40)),
+            String::from("mem[0] = 1"),
+            format!("mask = {}{}1", "0".repeat(60), "X".repeat(39)),
+            String::from("mem[0] = 2"),
+            format!("mask = {}", "0".repeat(100)),
+            String::from("mem[3] = 5"),
+        ];
+        for line in program.iter() {
+            puter.exec(&Command::from_line(line).unwrap());
+        }
+        assert_eq!(puter.sum(), Some((1 << 39) + 2 * (1 << 39) - 2 + 5));
+
+        assert!(Command::from_line(&format!("mask = {}", "X".repeat(129))).is_none());
+    }
+
+    fn sum_of<C: Computer>(lines: &[String]) -> Option<u128> {
+        let mut puter = C::new();
+        for line in lines.iter() {
+            puter.exec(&Command::from_line(line).unwrap());
+        }
+        puter.sum()
+    }
+
+    #[test]
+    fn sum_overflow() {
+        // Masks leaving values alone in part 1 and addresses in part 2
+        let near_max = |mask: char, last: u128| vec![
+            format!("mask = {}", mask.to_string().repeat(128)),
+            format!("mem[1] = {}", u128::MAX - 1),
+            format!("mem[2] = {}", last),
+        ];
+        assert_eq!(sum_of::<Computer1>(&near_max('X', 1)), Some(u128::MAX));
+        assert_eq!(sum_of::<Computer2>(&near_max('0', 1)), Some(u128::MAX));
+        assert_eq!(sum_of::<Computer1>(&near_max('X', 2)), None);
+        assert_eq!(sum_of::<Computer2>(&near_max('0', 2)), None);
+
+        // One floating bit writes the value twice
+        let doubled = vec![format!("mask = {}X", "0".repeat(127)), format!("mem[0] = {}", u128::MAX / 2 + 1)];
+        assert_eq!(sum_of::<Computer2>(&doubled), None);
+    }
+
     #[test]
     fn it_works() {
         drive::<Computer1>("res/14/input_simple.txt");
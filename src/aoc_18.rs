@@ -1,9 +1,181 @@
-#[derive(Debug, Clone)]
+use std::convert::TryFrom;
+use crate::num::Num;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Expression {
     Constant(usize),
     Add(Vec<Expression>),
     Mul(Vec<Expression>),
     AddMul(Vec<(Expression, char)>),
+    // Built by parse_with, evaluated against an OperatorTable
+    Binary(char, Box<Expression>, Box<Expression>),
+    Negate(Box<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone)]
+pub struct Operator {
+    pub symbol: char,
+    pub precedence: usize,
+    pub assoc: Assoc,
+    // None on overflow, division by zero etc.
+    pub func: fn(i64, i64) -> Option<i64>,
+}
+
+// Binary operators by symbol, plus where unary minus binds if it is allowed.
+#[derive(Debug, Clone, Default)]
+pub struct OperatorTable {
+    ops: Vec<Operator>,
+    unary_minus: Option<usize>,
+}
+
+impl OperatorTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_operator(mut self, symbol: char, precedence: usize, assoc: Assoc, func: fn(i64, i64) -> Option<i64>) -> Self {
+        self.ops.retain(|op| op.symbol != symbol);
+        self.ops.push(Operator { symbol, precedence, assoc, func });
+        self
+    }
+
+    pub fn with_unary_minus(mut self, precedence: usize) -> Self {
+        self.unary_minus = Some(precedence);
+        self
+    }
+
+    pub fn get(&self, symbol: char) -> Option<&Operator> {
+        self.ops.iter().find(|op| op.symbol == symbol)
+    }
+
+    // Part 1 rules: + and * bind equally, evaluated left to right
+    pub fn left_to_right() -> Self {
+        Self::new()
+            .with_operator('+', 1, Assoc::Left, i64::checked_add)
+            .with_operator('*', 1, Assoc::Left, i64::checked_mul)
+    }
+
+    // Part 2 rules: + binds tighter than *
+    pub fn addition_first() -> Self {
+        Self::new()
+            .with_operator('+', 2, Assoc::Left, i64::checked_add)
+            .with_operator('*', 1, Assoc::Left, i64::checked_mul)
+    }
+
+    // The usual school rules, with ^ for exponents
+    pub fn standard() -> Self {
+        Self::new()
+            .with_operator('+', 1, Assoc::Left, i64::checked_add)
+            .with_operator('-', 1, Assoc::Left, i64::checked_sub)
+            .with_operator('*', 2, Assoc::Left, i64::checked_mul)
+            .with_operator('/', 2, Assoc::Left, i64::checked_div)
+            .with_operator('^', 4, Assoc::Right, |a, b| a.checked_pow(u32::try_from(b).ok()?))
+            .with_unary_minus(3)
+    }
+}
+
+// Integers modulo M. Division multiplies by the inverse, so it only fails
+// when the divisor shares a factor with M. Exponents need the value before
+// reduction, so each ModInt also keeps that while it is a known u64, and
+// raising to any other exponent fails.
+#[derive(Debug, Clone, Copy)]
+pub struct ModInt<const M: u64> {
+    val: u64,
+    exact: Option<u64>,
+}
+
+impl<const M: u64> PartialEq for ModInt<M> {
+    fn eq(&self, other: &Self) -> bool {
+        self.val == other.val
+    }
+}
+
+impl<const M: u64> Eq for ModInt<M> {}
+
+impl<const M: u64> std::hash::Hash for ModInt<M> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.val.hash(state)
+    }
+}
+
+impl<const M: u64> ModInt<M> {
+    pub fn new(val: u64) -> Self {
+        Self { val: val % M, exact: Some(val) }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.val
+    }
+
+    // Reduced from a value that isn't known exactly
+    fn residue(val: u64) -> Self {
+        Self { val: val % M, exact: None }
+    }
+
+    // The exact result of op, when both sides are known exactly
+    fn exact_with(&self, other: &Self, op: fn(u64, u64) -> Option<u64>) -> Option<u64> {
+        op(self.exact?, other.exact?)
+    }
+
+    fn inverse(&self) -> Option<Self> {
+        crate::modarith::inverse_mod(self.val, M).map(Self::residue)
+    }
+}
+
+impl<const M: u64> Num for ModInt<M> {
+    fn from_usize(val: usize) -> Option<Self> {
+        Some(Self::new(val as u64))
+    }
+
+    fn checked_add(&self, other: &Self) -> Option<Self> {
+        let val = ((self.val as u128 + other.val as u128) % M as u128) as u64;
+        Some(Self { val, exact: self.exact_with(other, u64::checked_add) })
+    }
+
+    fn checked_sub(&self, other: &Self) -> Option<Self> {
+        let val = ((self.val as u128 + M as u128 - other.val as u128) % M as u128) as u64;
+        Some(Self { val, exact: self.exact_with(other, u64::checked_sub) })
+    }
+
+    fn checked_mul(&self, other: &Self) -> Option<Self> {
+        let val = crate::modarith::mul_mod(self.val, other.val, M);
+        Some(Self { val, exact: self.exact_with(other, u64::checked_mul) })
+    }
+
+    fn checked_div(&self, other: &Self) -> Option<Self> {
+        let val = self.checked_mul(&other.inverse()?)?.val;
+        // Only exact when the division is
+        let exact = self.exact_with(other, |a, b| if a.checked_rem(b)? == 0 { Some(a / b) } else { None });
+        Some(Self { val, exact })
+    }
+
+    fn checked_pow(&self, exp: &Self) -> Option<Self> {
+        let val = crate::modarith::pow_mod(self.val, exp.exact?, M);
+        let exact = self.exact.and_then(|base| base.checked_pow(u32::try_from(exp.exact?).ok()?));
+        Some(Self { val, exact })
+    }
+
+    fn checked_neg(&self) -> Option<Self> {
+        let val = (M - self.val) % M;
+        Some(Self { val, exact: self.exact.filter(|v| *v == 0) })
+    }
+}
+
+fn apply_op<N: Num>(op: char, lhs: &N, rhs: &N) -> Option<N> {
+    match op {
+        '+' => lhs.checked_add(rhs),
+        '-' => lhs.checked_sub(rhs),
+        '*' => lhs.checked_mul(rhs),
+        '/' => lhs.checked_div(rhs),
+        '^' => lhs.checked_pow(rhs),
+        _ => None,
+    }
 }
 
 fn trim_u8(mut t: &[u8]) -> &[u8] {
@@ -14,64 +186,218 @@ fn trim_u8(mut t: &[u8]) -> &[u8] {
     t
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnclosedParen,
+    UnmatchedParen,
+    DanglingOperator(char),
+    ExpectedOperand,
+    MissingOperator,
+    UnknownCharacter(char),
+    NumberOverflow,
+}
+
+// Where parsing went wrong, as a byte range into the input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            ParseErrorKind::UnclosedParen => write!(f, "unbalanced parentheses, '(' is never closed")?,
+            ParseErrorKind::UnmatchedParen => write!(f, "unbalanced parentheses, ')' has no matching '('")?,
+            ParseErrorKind::DanglingOperator(op) => write!(f, "operator '{}' is missing its right hand side", op)?,
+            ParseErrorKind::ExpectedOperand => write!(f, "expected a number or '('")?,
+            ParseErrorKind::MissingOperator => write!(f, "expected an operator")?,
+            ParseErrorKind::UnknownCharacter(c) => write!(f, "unknown character '{}'", c)?,
+            ParseErrorKind::NumberOverflow => write!(f, "number is too large")?,
+        }
+        write!(f, " at offset {}", self.offset)
+    }
+}
+
+impl ParseError {
+    // The message, the input, and carets under the offending bytes
+    pub fn render(&self, input: &str) -> String {
+        let col = input.get(..self.offset).map_or(self.offset, |s| s.chars().count());
+        format!("{}\n{}\n{}{}", self, input, " ".repeat(col), "^".repeat(self.len.max(1)))
+    }
+}
+
+// A ParseError still being passed up through the parser. Its position is
+// kept as the length of the remaining input until the top level knows what
+// that is an offset into.
+struct Fail {
+    kind: ParseErrorKind,
+    rem_len: usize,
+    len: usize,
+}
+
+impl Fail {
+    fn at(kind: ParseErrorKind, rem: &[u8], len: usize) -> Self {
+        Self { kind, rem_len: rem.len(), len }
+    }
+
+    fn into_error(self, input: &str) -> ParseError {
+        ParseError { kind: self.kind, offset: input.len() - self.rem_len, len: self.len }
+    }
+
+    // A missing operand straight after an operator is the operator's fault
+    fn after_operator(self, op: &[u8]) -> Self {
+        if self.kind == ParseErrorKind::ExpectedOperand && self.rem_len == trim_u8(&op[1..]).len() {
+            Self::at(ParseErrorKind::DanglingOperator(op[0] as char), op, 1)
+        } else {
+            self
+        }
+    }
+}
+
+fn unknown_character(rem: &[u8]) -> Fail {
+    let c = String::from_utf8_lossy(&rem[..rem.len().min(4)]).chars().next().unwrap_or('?');
+    Fail::at(ParseErrorKind::UnknownCharacter(c), rem, 1)
+}
+
+// Something other than an operand where one was needed
+fn expected_operand(rem: &[u8], is_operator: impl Fn(u8) -> bool) -> Fail {
+    match rem.first() {
+        Some(&c) if c != b')' && !is_operator(c) => unknown_character(rem),
+        _ => Fail::at(ParseErrorKind::ExpectedOperand, rem, 1),
+    }
+}
+
+// Something other than an operator where the expression should continue
+fn unexpected(rem: &[u8]) -> Fail {
+    match rem[0] {
+        b')' => Fail::at(ParseErrorKind::UnmatchedParen, rem, 1),
+        c if c.is_ascii_digit() || c == b'(' => Fail::at(ParseErrorKind::MissingOperator, rem, 1),
+        _ => unknown_character(rem),
+    }
+}
+
+// Checks for the ')' matching the '(' at the start of open
+fn close_paren<'a>(open: &[u8], rem: &'a [u8]) -> Result<&'a [u8], Fail> {
+    let rem = trim_u8(rem);
+    match rem.first() {
+        Some(b')') => Ok(&rem[1..]),
+        Some(_) => Err(unexpected(rem)),
+        None => Err(Fail::at(ParseErrorKind::UnclosedParen, open, 1)),
+    }
+}
+
+fn is_add_mul(c: u8) -> bool {
+    c == b'+' || c == b'*'
+}
+
 impl Expression {
-    fn num_from_str(mut input: &[u8]) -> (Self, &[u8]) {
-        let mut acc = 0;
-        while input.len() > 0 && (input[0] as char).is_digit(10) {
-            acc *= 10;
-            acc += (input[0] - '0' as u8) as usize;
-            input = &input[1..];
+    fn num_from_str(input: &[u8], is_operator: impl Fn(u8) -> bool) -> Result<(Self, &[u8]), Fail> {
+        let len = input.iter().position(|b| !b.is_ascii_digit()).unwrap_or(input.len());
+        if len == 0 {
+            return Err(expected_operand(input, is_operator));
         }
 
-        (Self::Constant(acc), trim_u8(input))
+        let acc = input[..len].iter()
+            .try_fold(0usize, |acc, b| acc.checked_mul(10)?.checked_add((b - b'0') as usize))
+            .ok_or_else(|| Fail::at(ParseErrorKind::NumberOverflow, input, len))?;
+
+        Ok((Self::Constant(acc), trim_u8(&input[len..])))
     }
 
-    fn parse_operand(mut rem: &[u8]) -> (Self, &[u8]) {
+    fn parse_operand(mut rem: &[u8]) -> Result<(Self, &[u8]), Fail> {
         rem = trim_u8(rem);
-        let c = rem[0] as char;
-        if c == '(' {
-            let (lhs, mut rem) = Self::parse_bytes(&rem[1..]).unwrap();
-            // look for closing paren
-            rem = trim_u8(rem);
-            assert_eq!(rem[0], ')' as u8);
-            (lhs, trim_u8(&rem[1..]))
+        if rem.first() == Some(&b'(') {
+            let (lhs, inner_rem) = Self::parse_bytes(&rem[1..])?;
+            Ok((lhs, trim_u8(close_paren(rem, inner_rem)?)))
         } else {
-            // Know you have at least 1 lhs
-            Self::num_from_str(rem)
+            Self::num_from_str(rem, is_add_mul)
         }
     }
 
-    fn parse_bytes(mut rem: &[u8]) -> Option<(Self, &[u8])> {
+    fn parse_bytes(mut rem: &[u8]) -> Result<(Self, &[u8]), Fail> {
         rem = trim_u8(rem);
-        let (lhs, mut rem) = Self::parse_operand(rem);
-
-        if rem.len() == 0 {
-            return Some((lhs, rem));
-        }
+        let (lhs, mut rem) = Self::parse_operand(rem)?;
 
-        match rem[0] as char{
-            '+' | '*' => (),
-            _ => {
-                // This guy is done
-                return Some((lhs, rem));
-            }
+        if !rem.first().copied().is_some_and(is_add_mul) {
+            // This guy is done
+            return Ok((lhs, rem));
         }
 
         let mut args = vec![(lhs, '+')];
-        while rem.len() > 0 && (rem[0] == '+' as u8 || rem[0] == '*' as u8) {
-            let (operand, inner_rem) = Self::parse_operand(&rem[1..]);
+        while rem.first().copied().is_some_and(is_add_mul) {
+            let (operand, inner_rem) = Self::parse_operand(&rem[1..]).map_err(|e| e.after_operator(rem))?;
             args.push((operand, rem[0] as char));
             rem = trim_u8(inner_rem);
         }
 
-        Some((Self::AddMul(args), trim_u8(rem)))
+        Ok((Self::AddMul(args), trim_u8(rem)))
+    }
+
+    // Parses with + and * as a flat chain, see set_precedences
+    pub fn try_from_str(input: &str) -> Result<Self, ParseError> {
+        let (exp, rem) = Self::parse_bytes(input.as_bytes()).map_err(|e| e.into_error(input))?;
+        if rem.is_empty() {
+            Ok(exp)
+        } else {
+            Err(unexpected(rem).into_error(input))
+        }
     }
 
     pub fn from_str(input: &str) -> Option<Self> {
-        if let Some(exp) = Self::parse_bytes(&input.as_bytes()) {
-            Some(exp.0)
+        Self::try_from_str(input).ok()
+    }
+
+    fn parse_atom<'a>(rem: &'a [u8], table: &OperatorTable) -> Result<(Self, &'a [u8]), Fail> {
+        let rem = trim_u8(rem);
+        let is_operator = |c: u8| table.get(c as char).is_some();
+        match rem.first() {
+            Some(b'(') => {
+                let (inner, inner_rem) = Self::parse_climb(&rem[1..], table, 0)?;
+                Ok((inner, close_paren(rem, inner_rem)?))
+            },
+            Some(b'-') if table.unary_minus.is_some() => {
+                let precedence = table.unary_minus.unwrap();
+                let (operand, inner_rem) = Self::parse_climb(&rem[1..], table, precedence)
+                    .map_err(|e| e.after_operator(rem))?;
+                Ok((Self::Negate(Box::new(operand)), inner_rem))
+            },
+            _ => Self::num_from_str(rem, is_operator),
+        }
+    }
+
+    // Precedence climbing: parse an operand, then keep folding in operators
+    // that bind at least as tightly as min_precedence.
+    fn parse_climb<'a>(rem: &'a [u8], table: &OperatorTable, min_precedence: usize) -> Result<(Self, &'a [u8]), Fail> {
+        let (mut lhs, mut rem) = Self::parse_atom(rem, table)?;
+        loop {
+            rem = trim_u8(rem);
+            let op = match rem.first().and_then(|c| table.get(*c as char)) {
+                Some(op) if op.precedence >= min_precedence => op,
+                _ => break,
+            };
+
+            let next_min = match op.assoc {
+                Assoc::Left => op.precedence + 1,
+                Assoc::Right => op.precedence,
+            };
+            let (rhs, inner_rem) = Self::parse_climb(&rem[1..], table, next_min)
+                .map_err(|e| e.after_operator(rem))?;
+            lhs = Self::Binary(op.symbol, Box::new(lhs), Box::new(rhs));
+            rem = inner_rem;
+        }
+
+        Ok((lhs, rem))
+    }
+
+    pub fn parse_with(input: &str, table: &OperatorTable) -> Result<Self, ParseError> {
+        let (exp, rem) = Self::parse_climb(input.as_bytes(), table, 0).map_err(|e| e.into_error(input))?;
+        let rem = trim_u8(rem);
+        if rem.is_empty() {
+            Ok(exp)
         } else {
-            None
+            Err(unexpected(rem).into_error(input))
         }
     }
 
@@ -108,50 +434,238 @@ impl Expression {
             },
             Self::Add(args) => Self::Add(args.to_vec()),
             Self::Mul(args) => Self::Mul(args.to_vec()),
+            Self::Binary(op, lhs, rhs) => Self::Binary(*op, Box::new(lhs.set_precedences()), Box::new(rhs.set_precedences())),
+            Self::Negate(arg) => Self::Negate(Box::new(arg.set_precedences())),
         }
     }
 
-    pub fn eval(&self) -> usize {
+    // None if an operator isn't + or *. Binary and Negate go through
+    // eval_table with the standard operators, so are also None if they come
+    // out negative or overflow.
+    pub fn eval(&self) -> Option<usize> {
         match self {
             Self::AddMul(args) => {
-                let mut acc = 0;
+                let mut acc = 0usize;
                 for (arg, op) in args.iter() {
-                    let val = arg.eval();
+                    let val = arg.eval()?;
                     match op {
-                        '+' => {acc += val},
-                        '*' => {acc *= val},
-                        _ => {panic!("Bad operator");}
+                        '+' => {acc = acc.checked_add(val)?},
+                        '*' => {acc = acc.checked_mul(val)?},
+                        _ => {return None;}
                     }
                 }
-                acc
+                Some(acc)
             },
             Self::Constant(val) => {
-                *val
+                Some(*val)
             },
             Self::Mul(args) => {
-                args.iter().fold(1, |acc, arg| acc * arg.eval())
+                args.iter().try_fold(1usize, |acc, arg| acc.checked_mul(arg.eval()?))
             },
             Self::Add(args) => {
-                args.iter().fold(0, |acc, arg| acc + arg.eval())
+                args.iter().try_fold(0usize, |acc, arg| acc.checked_add(arg.eval()?))
+            },
+            Self::Binary(..) | Self::Negate(_) => {
+                usize::try_from(self.eval_table(&OperatorTable::standard())?).ok()
             }
         }
     }
+
+    // Checked evaluation, returns None on overflow or if an operator isn't
+    // in the table.
+    pub fn eval_table(&self, table: &OperatorTable) -> Option<i64> {
+        match self {
+            Self::Constant(val) => i64::try_from(*val).ok(),
+            Self::Add(args) => {
+                args.iter().try_fold(0i64, |acc, arg| acc.checked_add(arg.eval_table(table)?))
+            },
+            Self::Mul(args) => {
+                args.iter().try_fold(1i64, |acc, arg| acc.checked_mul(arg.eval_table(table)?))
+            },
+            Self::AddMul(args) => {
+                args.iter().try_fold(0i64, |acc, (arg, op)| {
+                    (table.get(*op)?.func)(acc, arg.eval_table(table)?)
+                })
+            },
+            Self::Binary(op, lhs, rhs) => {
+                (table.get(*op)?.func)(lhs.eval_table(table)?, rhs.eval_table(table)?)
+            },
+            Self::Negate(arg) => arg.eval_table(table)?.checked_neg(),
+        }
+    }
+
+    // Checked evaluation in any Num type, using the usual meanings of
+    // + - * / ^. None on overflow or any other operator.
+    pub fn eval_with<N: Num>(&self) -> Option<N> {
+        match self {
+            Self::Constant(val) => N::from_usize(*val),
+            Self::Add(args) => {
+                args.iter().try_fold(N::from_usize(0)?, |acc, arg| acc.checked_add(&arg.eval_with()?))
+            },
+            Self::Mul(args) => {
+                args.iter().try_fold(N::from_usize(1)?, |acc, arg| acc.checked_mul(&arg.eval_with()?))
+            },
+            Self::AddMul(args) => {
+                args.iter().try_fold(N::from_usize(0)?, |acc, (arg, op)| apply_op(*op, &acc, &arg.eval_with()?))
+            },
+            Self::Binary(op, lhs, rhs) => apply_op(*op, &lhs.eval_with()?, &rhs.eval_with()?),
+            Self::Negate(arg) => arg.eval_with::<N>()?.checked_neg(),
+        }
+    }
+}
+
+// An Expression rendered with as few parentheses as the table allows, so
+// that parsing it back with the same table gives the same tree.
+pub struct Printed<'a> {
+    exp: &'a Expression,
+    table: &'a OperatorTable,
+}
+
+impl<'a> std::fmt::Display for Printed<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.exp.to_binary().write_with(f, self.table, None)
+    }
+}
+
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.display(&OperatorTable::standard()).fmt(f)
+    }
+}
+
+impl Expression {
+    pub fn display<'a>(&'a self, table: &'a OperatorTable) -> Printed<'a> {
+        Printed { exp: self, table }
+    }
+
+    pub fn to_sexpr(&self) -> String {
+        let join = |args: Vec<String>| args.join(" ");
+        match self {
+            Self::Constant(val) => val.to_string(),
+            Self::Add(args) => format!("(+ {})", join(args.iter().map(|a| a.to_sexpr()).collect())),
+            Self::Mul(args) => format!("(* {})", join(args.iter().map(|a| a.to_sexpr()).collect())),
+            Self::AddMul(args) => {
+                let mut parts = vec![];
+                for (i, (arg, op)) in args.iter().enumerate() {
+                    if i > 0 {
+                        parts.push(op.to_string());
+                    }
+                    parts.push(arg.to_sexpr());
+                }
+                format!("(chain {})", join(parts))
+            },
+            Self::Binary(op, lhs, rhs) => format!("({} {} {})", op, lhs.to_sexpr(), rhs.to_sexpr()),
+            Self::Negate(arg) => format!("(neg {})", arg.to_sexpr()),
+        }
+    }
+
+    // Rewrites the n-ary variants as the equivalent left leaning Binary trees
+    fn to_binary(&self) -> Self {
+        let chain = |args: Vec<(Self, char)>| {
+            let mut args = args.into_iter();
+            let first = args.next().map(|(arg, _)| arg).unwrap_or(Self::Constant(0));
+            args.fold(first, |acc, (arg, op)| Self::Binary(op, Box::new(acc), Box::new(arg)))
+        };
+        match self {
+            Self::Constant(val) => Self::Constant(*val),
+            Self::Add(args) => chain(args.iter().map(|a| (a.to_binary(), '+')).collect()),
+            Self::Mul(args) => chain(args.iter().map(|a| (a.to_binary(), '*')).collect()),
+            Self::AddMul(args) => chain(args.iter().map(|(a, op)| (a.to_binary(), *op)).collect()),
+            Self::Binary(op, lhs, rhs) => Self::Binary(*op, Box::new(lhs.to_binary()), Box::new(rhs.to_binary())),
+            Self::Negate(arg) => Self::Negate(Box::new(arg.to_binary())),
+        }
+    }
+
+    fn binary_precedence(&self, table: &OperatorTable) -> Option<(usize, Assoc)> {
+        match self {
+            // Operators missing from the table get parenthesized everywhere
+            Self::Binary(op, _, _) => Some(table.get(*op).map_or((0, Assoc::Left), |op| (op.precedence, op.assoc))),
+            _ => None,
+        }
+    }
+
+    // follow is the precedence of the operator printed right after this
+    // expression, if any. A unary minus would swallow it if it binds as
+    // tightly as the minus does.
+    fn write_with(&self, f: &mut std::fmt::Formatter<'_>, table: &OperatorTable, follow: Option<usize>) -> std::fmt::Result {
+        match self {
+            Self::Binary(op, lhs, rhs) => {
+                let (precedence, assoc) = self.binary_precedence(table).unwrap();
+                let lhs_parens = match lhs.binary_precedence(table) {
+                    Some((p, _)) => p < precedence || (p == precedence && assoc == Assoc::Right),
+                    None => false,
+                };
+                let rhs_parens = match rhs.binary_precedence(table) {
+                    Some((p, _)) => p < precedence || (p == precedence && assoc == Assoc::Left),
+                    None => false,
+                };
+
+                if lhs_parens {
+                    write!(f, "(")?;
+                    lhs.write_with(f, table, None)?;
+                    write!(f, ")")?;
+                } else {
+                    lhs.write_with(f, table, Some(precedence))?;
+                }
+                write!(f, " {} ", op)?;
+                if rhs_parens {
+                    write!(f, "(")?;
+                    rhs.write_with(f, table, None)?;
+                    write!(f, ")")
+                } else {
+                    rhs.write_with(f, table, follow)
+                }
+            },
+            Self::Negate(arg) => {
+                let unary = table.unary_minus.unwrap_or(usize::MAX);
+                let parens = follow.is_some_and(|p| p >= unary);
+                let follow = if parens { None } else { follow };
+                if parens {
+                    write!(f, "(")?;
+                }
+                write!(f, "-")?;
+                match arg.binary_precedence(table) {
+                    Some((p, _)) if p < unary => {
+                        write!(f, "(")?;
+                        arg.write_with(f, table, None)?;
+                        write!(f, ")")?;
+                    },
+                    _ => arg.write_with(f, table, follow)?,
+                }
+                if parens {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            },
+            Self::Constant(val) => write!(f, "{}", val),
+            _ => self.to_binary().write_with(f, table, follow),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bigint::BigInt;
 
     fn drive(filename: &str) {
         let input = std::fs::read_to_string(filename).unwrap();
         let sum = input.lines().fold(0, |acc, line|
-            acc + Expression::from_str(line).unwrap().eval()
+            acc + Expression::from_str(line).unwrap().eval().unwrap()
         );
         println!("Part 1: {}", sum);
-        let sum = input.lines().fold(0, |acc, line|
-            acc + Expression::from_str(line).unwrap().set_precedences().eval()
+        let sum_2 = input.lines().fold(0, |acc, line|
+            acc + Expression::from_str(line).unwrap().set_precedences().eval().unwrap()
         );
-        println!("Part 2: {}", sum);
+        println!("Part 2: {}", sum_2);
+
+        // The preset tables agree with both parts
+        for (table, expected) in [(OperatorTable::left_to_right(), sum), (OperatorTable::addition_first(), sum_2)].iter() {
+            let total = input.lines().fold(0, |acc, line|
+                acc + Expression::parse_with(line, table).unwrap().eval_table(table).unwrap()
+            );
+            assert_eq!(total as usize, *expected);
+        }
     }
 
     #[test]
@@ -167,12 +681,195 @@ mod tests {
         ];
         for (test, test_val, test_val_2) in &test_strs {
             let exp = Expression::from_str(test).unwrap();
-            assert_eq!(exp.eval(), *test_val);
+            assert_eq!(exp.eval(), Some(*test_val));
             let exp = exp.set_precedences();
-            assert_eq!(exp.eval(), *test_val_2);
+            assert_eq!(exp.eval(), Some(*test_val_2));
+
+            let exp = Expression::parse_with(test, &OperatorTable::left_to_right()).unwrap();
+            assert_eq!(exp.eval_table(&OperatorTable::left_to_right()), Some(*test_val as i64));
+            let exp = Expression::parse_with(test, &OperatorTable::addition_first()).unwrap();
+            assert_eq!(exp.eval_table(&OperatorTable::addition_first()), Some(*test_val_2 as i64));
+        }
+    }
+
+    // Random expression trees using the operators in ops
+    fn random_expression(seed: &mut u64, depth: usize, ops: &[char], negate: bool) -> Expression {
+        *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let roll = (*seed >> 33) as usize;
+        match (depth, roll % 4) {
+            (0, _) | (_, 0) => Expression::Constant(roll % 10),
+            (_, 1) if negate => Expression::Negate(Box::new(random_expression(seed, depth - 1, ops, negate))),
+            _ => {
+                let op = ops[(roll / 4) % ops.len()];
+                let lhs = random_expression(seed, depth - 1, ops, negate);
+                let rhs = random_expression(seed, depth - 1, ops, negate);
+                Expression::Binary(op, Box::new(lhs), Box::new(rhs))
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let tables = [
+            (OperatorTable::left_to_right(), vec!['+', '*'], false),
+            (OperatorTable::addition_first(), vec!['+', '*'], false),
+            (OperatorTable::standard(), vec!['+', '-', '*', '/', '^'], true),
+            (OperatorTable::standard().with_operator('@', 5, Assoc::Left, i64::checked_sub), vec!['-', '^', '@'], true),
+        ];
+
+        let mut seed = 2020;
+        for (table, ops, negate) in tables.iter() {
+            for _ in 0..500 {
+                let exp = random_expression(&mut seed, 5, ops, *negate);
+                let printed = exp.display(table).to_string();
+                assert_eq!(Expression::parse_with(&printed, table), Ok(exp.clone()), "{} from {}", printed, exp.to_sexpr());
+            }
         }
     }
 
+    #[test]
+    fn printing() {
+        let table = OperatorTable::standard();
+        for s in ["1 - 2 - 3", "1 - (2 - 3)", "2 ^ 3 ^ 2", "(2 ^ 3) ^ 2", "-2 ^ 2", "(-2) ^ 2", "-(1 + 2) * 3", "1 * -2 + 3"].iter() {
+            assert_eq!(Expression::parse_with(s, &table).unwrap().to_string(), *s);
+        }
+        assert_eq!(Expression::parse_with("((1)) + (2 * 3)", &table).unwrap().to_string(), "1 + 2 * 3");
+        assert_eq!(Expression::parse_with("1 + 2 * -3", &table).unwrap().to_sexpr(), "(+ 1 (* 2 (neg 3)))");
+
+        // See what set_precedences did to a chain
+        let exp = Expression::from_str("2 * 3 + (4 * 5)").unwrap();
+        assert_eq!(exp.to_sexpr(), "(chain 2 * 3 + (chain 4 * 5))");
+        assert_eq!(exp.display(&OperatorTable::left_to_right()).to_string(), "2 * 3 + (4 * 5)");
+        let exp = exp.set_precedences();
+        assert_eq!(exp.to_sexpr(), "(* 2 (+ 3 (* 4 5)))");
+        assert_eq!(exp.display(&OperatorTable::addition_first()).to_string(), "2 * 3 + (4 * 5)");
+        assert_eq!(exp.display(&OperatorTable::left_to_right()).to_string(), "2 * (3 + (4 * 5))");
+    }
+
+    #[test]
+    fn diagnostics() {
+        let table = OperatorTable::standard();
+        let cases: [(&str, ParseErrorKind, usize); 11] = [
+            ("(1 + 2", ParseErrorKind::UnclosedParen, 0),
+            ("1 + (2 * (3 + 4)", ParseErrorKind::UnclosedParen, 4),
+            ("1 + 2)", ParseErrorKind::UnmatchedParen, 5),
+            ("1 + 2 *", ParseErrorKind::DanglingOperator('*'), 6),
+            ("(1 + ) * 2", ParseErrorKind::DanglingOperator('+'), 3),
+            ("1 + -", ParseErrorKind::DanglingOperator('-'), 4),
+            ("", ParseErrorKind::ExpectedOperand, 0),
+            ("()", ParseErrorKind::ExpectedOperand, 1),
+            ("1 2", ParseErrorKind::MissingOperator, 2),
+            ("1 + x", ParseErrorKind::UnknownCharacter('x'), 4),
+            ("2 * 99999999999999999999", ParseErrorKind::NumberOverflow, 4),
+        ];
+        for (input, kind, offset) in cases.iter() {
+            let err = Expression::parse_with(input, &table).unwrap_err();
+            assert_eq!((err.kind, err.offset), (*kind, *offset), "{}", input);
+        }
+
+        // The flat + and * parser reports the same way
+        assert_eq!(Expression::try_from_str("1 - 2").unwrap_err().kind, ParseErrorKind::UnknownCharacter('-'));
+        assert_eq!(Expression::try_from_str("(3 * ").unwrap_err().kind, ParseErrorKind::DanglingOperator('*'));
+        assert_eq!(Expression::try_from_str("((3)").unwrap_err().offset, 0);
+        assert_eq!(Expression::try_from_str("3)").unwrap_err().kind, ParseErrorKind::UnmatchedParen);
+        assert!(Expression::from_str("* 3").is_none());
+
+        let input = "2 * 99999999999999999999 + 1";
+        assert_eq!(
+            Expression::parse_with(input, &table).unwrap_err().render(input),
+            concat!(
+                "number is too large at offset 4\n",
+                "2 * 99999999999999999999 + 1\n",
+                "    ^^^^^^^^^^^^^^^^^^^^",
+            ),
+        );
+        let input = "(1 + é)";
+        assert_eq!(
+            Expression::parse_with(input, &table).unwrap_err().render(input),
+            "unknown character 'é' at offset 5\n(1 + é)\n     ^",
+        );
+    }
+
+    #[test]
+    fn numeric_types() {
+        let table = OperatorTable::standard();
+        let exp = Expression::parse_with("99999999999 * 99999999999 * 99999999999 - 1", &table).unwrap();
+        assert_eq!(exp.eval_with::<i64>(), None);
+        assert_eq!(exp.eval_with::<i128>(), Some(999999999970000000000299999999998));
+        assert_eq!(exp.eval_with::<BigInt>().unwrap().to_string(), "999999999970000000000299999999998");
+        assert_eq!(exp.eval_with::<ModInt<1_000_000_007>>().unwrap().value(), 999999999970000000000299999999998u128.rem_euclid(1_000_000_007) as u64);
+
+        let exp = Expression::parse_with("(2 ^ 130 - 3 ^ 90) / 7", &table).unwrap();
+        assert_eq!(exp.eval_with::<i128>(), None);
+        let big = exp.eval_with::<BigInt>().unwrap();
+        assert_eq!(big.to_string(), "-1246657491231432667433934854435285752456517");
+        assert_eq!(big.rem_u64(1009), 42);
+
+        // Modular division is multiplication by the inverse
+        let exp = Expression::parse_with("1 / 3 - 2", &table).unwrap();
+        assert_eq!(exp.eval_with::<ModInt<7>>().unwrap().value(), 3);
+        assert_eq!(Expression::parse_with("1 / 7", &table).unwrap().eval_with::<ModInt<7>>(), None);
+        assert_eq!(Expression::parse_with("1 / 0", &table).unwrap().eval_with::<BigInt>(), None);
+
+        // Exponents are used exactly, not as residues
+        let modular = |s: &str| Expression::parse_with(s, &table).unwrap().eval_with::<ModInt<7>>().map(|m| m.value());
+        assert_eq!(modular("2 ^ 10"), Some(2));
+        assert_eq!(modular("3 ^ (2 * 4 - 1)"), Some(3));
+        assert_eq!(modular("2 ^ 2 ^ 5"), Some(4));
+        assert_eq!(modular("2 ^ -1"), None);
+        assert_eq!(modular("2 ^ (1 - 2)"), None);
+        assert_eq!(modular("2 ^ (5 / 3)"), None);
+        assert_eq!(modular("2 ^ (6 / 3)"), Some(4));
+        assert_eq!(ModInt::<7>::new(10), ModInt::<7>::new(3));
+
+        // Agrees with the plain evaluators on the homework
+        let input = std::fs::read_to_string("res/18/input.txt").unwrap();
+        for line in input.lines() {
+            let exp = Expression::from_str(line).unwrap().set_precedences();
+            assert_eq!(exp.eval_with::<i128>(), exp.eval().map(|v| v as i128));
+            assert_eq!(exp.eval_with::<BigInt>().and_then(|b| b.to_i128()), exp.eval().map(|v| v as i128));
+        }
+    }
+
+    #[test]
+    fn operator_tables() {
+        let table = OperatorTable::standard();
+        let eval = |s: &str| Expression::parse_with(s, &table).ok().and_then(|e| e.eval_table(&table));
+        assert_eq!(eval("2 - 3 - 4"), Some(-5));
+        assert_eq!(eval("2 ^ 3 ^ 2"), Some(512));
+        assert_eq!(eval("-2 ^ 2"), Some(-4));
+        assert_eq!(eval("(-2) ^ 2"), Some(4));
+        assert_eq!(eval("2 * -3 + 1"), Some(-5));
+        assert_eq!(eval("- -7 / 2"), Some(3));
+        assert_eq!(eval("1 + 2 * 3 - 8 / 4"), Some(5));
+
+        // Checked arithmetic
+        assert_eq!(eval("1 / 0"), None);
+        assert_eq!(eval("2 ^ 63"), None);
+        assert_eq!(eval("9223372036854775807 + 1"), None);
+        assert_eq!(eval("99999999999999999999"), None);
+
+        // The plain evaluator hands these trees to the standard table
+        let plain = |s: &str| Expression::parse_with(s, &table).unwrap().eval();
+        assert_eq!(plain("6 / 3"), Some(2));
+        assert_eq!(plain("- -7"), Some(7));
+        assert_eq!(plain("1 - 2"), None);
+        assert_eq!(plain("-1"), None);
+        assert_eq!(Expression::AddMul(vec![(Expression::Constant(1), '+'), (Expression::Constant(2), '-')]).eval(), None);
+
+        // Bad input
+        assert_eq!(eval("(1 + 2"), None);
+        assert_eq!(eval("1 +"), None);
+        assert_eq!(eval("1 2"), None);
+        assert!(Expression::parse_with("-1", &OperatorTable::left_to_right()).is_err());
+
+        // A user defined operator: a % b, binding loosest of all
+        let table = OperatorTable::standard()
+            .with_operator('%', 0, Assoc::Left, i64::checked_rem_euclid);
+        let exp = Expression::parse_with("17 % 2 + 3", &table).unwrap();
+        assert_eq!(exp.eval_table(&table), Some(2));
+    }
+
     #[test]
     fn test_it() {
         drive("res/18/input.txt");
@@ -1,12 +1,24 @@
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Rule {
     Char(char),
+    // Multi character literal, "abc"
+    Str(String),
+    // [a-z_], or anything but those with [^a-z_]
+    Class { negated: bool, ranges: Vec<(char, char)> },
     Concat(Vec<usize>),
+    // Sequence of anything else, e.g. 1 "x" (2 | 3)+
+    Seq(Vec<Rule>),
     Or(Vec<Rule>),
+    // At least min and at most max (None for no limit) repeats: ? * + {m,n}
+    Repeat(Box<Rule>, usize, Option<usize>),
+}
+
+fn class_matches(negated: bool, ranges: &[(char, char)], c: char) -> bool {
+    ranges.iter().any(|(lo, hi)| *lo <= c && c <= *hi) != negated
 }
 
 #[derive(Debug, Clone)]
@@ -14,49 +26,784 @@ pub struct Automaton {
     rules: HashMap<usize, Rule>,
 }
 
-impl Rule {
-    fn parse_concat(rem: &str) -> Option<Self> {
-        let nums = rem
-            .split(" ")
-            .map(|val| val.parse::<usize>().unwrap())
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Symbol {
+    Term(char),
+    // Index into Grammar::classes
+    Class(usize),
+    NonTerm(usize),
+}
+
+// The rules flattened into plain productions, rule -> symbols, for Earley.
+// Groups and repeats get helper rules numbered from first_synthetic up.
+struct Grammar {
+    prods: Vec<(usize, Vec<Symbol>)>,
+    by_lhs: HashMap<usize, Vec<usize>>,
+    nullable: HashSet<usize>,
+    classes: Vec<(bool, Vec<(char, char)>)>,
+    first_synthetic: usize,
+    next_id: usize,
+}
+
+// Earley item: production, how far into it, and where it started
+type Item = (usize, usize, usize);
+
+impl Grammar {
+    fn add_prod(&mut self, lhs: usize, rhs: Vec<Symbol>) {
+        self.by_lhs.entry(lhs).or_default().push(self.prods.len());
+        self.prods.push((lhs, rhs));
+    }
+
+    fn fresh(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    // Symbols for one item of a sequence
+    fn symbols(&mut self, rule: &Rule) -> Vec<Symbol> {
+        match rule {
+            Rule::Char(c) => vec![Symbol::Term(*c)],
+            Rule::Str(s) => s.chars().map(Symbol::Term).collect(),
+            Rule::Class { negated, ranges } => {
+                self.classes.push((*negated, ranges.clone()));
+                vec![Symbol::Class(self.classes.len() - 1)]
+            },
+            Rule::Concat(ids) => ids.iter().map(|id| Symbol::NonTerm(*id)).collect(),
+            Rule::Seq(items) => items.iter().flat_map(|item| self.symbols(item)).collect(),
+            Rule::Or(_) => {
+                let id = self.fresh();
+                self.add_rule(id, rule);
+                vec![Symbol::NonTerm(id)]
+            },
+            Rule::Repeat(inner, min, max) => {
+                let item = self.fresh();
+                self.add_rule(item, inner);
+                let id = self.fresh();
+                match max {
+                    Some(max) => {
+                        for count in *min..=*max {
+                            self.add_prod(id, vec![Symbol::NonTerm(item); count]);
+                        }
+                    },
+                    // id: item{min} | id item
+                    None => {
+                        self.add_prod(id, vec![Symbol::NonTerm(item); *min]);
+                        self.add_prod(id, vec![Symbol::NonTerm(id), Symbol::NonTerm(item)]);
+                    },
+                }
+                vec![Symbol::NonTerm(id)]
+            },
+        }
+    }
+
+    fn add_rule(&mut self, lhs: usize, rule: &Rule) {
+        if let Rule::Or(alts) = rule {
+            for alt in alts.iter() {
+                self.add_rule(lhs, alt);
+            }
+        } else {
+            let rhs = self.symbols(rule);
+            self.add_prod(lhs, rhs);
+        }
+    }
+
+    fn scans(&self, symbol: Symbol, c: char) -> bool {
+        match symbol {
+            Symbol::Term(t) => t == c,
+            Symbol::Class(idx) => class_matches(self.classes[idx].0, &self.classes[idx].1, c),
+            Symbol::NonTerm(_) => false,
+        }
+    }
+
+    fn from_rules(rules: &HashMap<usize, Rule>) -> Self {
+        let first_synthetic = rules.keys().max().map_or(0, |id| id + 1);
+        let mut grammar = Self {
+            prods: vec![],
+            by_lhs: HashMap::new(),
+            nullable: HashSet::new(),
+            classes: vec![],
+            first_synthetic,
+            next_id: first_synthetic,
+        };
+        for (id, rule) in rules.iter() {
+            grammar.add_rule(*id, rule);
+        }
+
+        // Rules that can match the empty string, grown until it settles
+        loop {
+            let before = grammar.nullable.len();
+            for (lhs, rhs) in grammar.prods.iter() {
+                if rhs.iter().all(|s| matches!(s, Symbol::NonTerm(id) if grammar.nullable.contains(id))) {
+                    grammar.nullable.insert(*lhs);
+                }
+            }
+            if grammar.nullable.len() == before {
+                break;
+            }
+        }
+
+        grammar
+    }
+
+    // Earley chart for matching start against the input. Works for any
+    // grammar, left recursive and ambiguous ones included.
+    fn chart(&self, start: usize, input: &[char]) -> Vec<Vec<Item>> {
+        let mut sets: Vec<Vec<Item>> = vec![vec![]; input.len() + 1];
+        let mut seen: Vec<HashSet<Item>> = vec![HashSet::new(); input.len() + 1];
+        let add = |sets: &mut Vec<Vec<Item>>, seen: &mut Vec<HashSet<Item>>, i: usize, item: Item| {
+            if seen[i].insert(item) {
+                sets[i].push(item);
+            }
+        };
+
+        for p in self.by_lhs.get(&start).map_or(&[][..], |ps| &ps[..]) {
+            add(&mut sets, &mut seen, 0, (*p, 0, 0));
+        }
+
+        for i in 0..=input.len() {
+            let mut j = 0;
+            while j < sets[i].len() {
+                let (p, dot, origin) = sets[i][j];
+                let (lhs, rhs) = &self.prods[p];
+                match rhs.get(dot) {
+                    // Predict
+                    Some(Symbol::NonTerm(id)) => {
+                        for q in self.by_lhs.get(id).map_or(&[][..], |qs| &qs[..]) {
+                            add(&mut sets, &mut seen, i, (*q, 0, i));
+                        }
+                        if self.nullable.contains(id) {
+                            add(&mut sets, &mut seen, i, (p, dot + 1, origin));
+                        }
+                    },
+                    // Scan
+                    Some(symbol) => {
+                        if matches!(input.get(i), Some(c) if self.scans(*symbol, *c)) {
+                            add(&mut sets, &mut seen, i + 1, (p, dot + 1, origin));
+                        }
+                    },
+                    // Complete
+                    None => {
+                        let waiting: Vec<Item> = sets[origin].iter()
+                            .filter(|(q, d, _)| self.prods[*q].1.get(*d) == Some(&Symbol::NonTerm(*lhs)))
+                            .copied()
+                            .collect();
+                        for (q, d, o) in waiting {
+                            add(&mut sets, &mut seen, i, (q, d + 1, o));
+                        }
+                    },
+                }
+                j += 1;
+            }
+        }
+
+        sets
+    }
+
+    fn accepts(&self, start: usize, input: &[char]) -> bool {
+        let sets = self.chart(start, input);
+        sets[input.len()].iter().any(|&(p, dot, origin)| {
+            origin == 0 && self.prods[p].0 == start && dot == self.prods[p].1.len()
+        })
+    }
+}
+
+// Which rule matched which span of the message, spans are in chars and
+// end exclusive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseTree {
+    pub rule: usize,
+    pub start: usize,
+    pub end: usize,
+    pub children: Vec<ParseTree>,
+}
+
+impl ParseTree {
+    // All (rule, start, end) in the tree, parents before children
+    pub fn spans(&self) -> Vec<(usize, usize, usize)> {
+        let mut out = vec![(self.rule, self.start, self.end)];
+        for child in self.children.iter() {
+            out.extend(child.spans());
+        }
+        out
+    }
+
+    // S-expression with the matched text of the leaves, e.g. (0 (1 "a") (2 "b"))
+    pub fn render(&self, input: &str) -> String {
+        if self.children.is_empty() {
+            let text: String = input.chars().skip(self.start).take(self.end - self.start).collect();
+            return format!("({} {:?})", self.rule, text);
+        }
+        let children: Vec<String> = self.children.iter().map(|c| c.render(input)).collect();
+        format!("({} {})", self.rule, children.join(" "))
+    }
+}
+
+// Walks a finished Earley chart back into derivations. Derivations that go
+// round a cycle (a rule deriving itself over the same span) are skipped,
+// there would be infinitely many of those.
+struct Forest<'a> {
+    grammar: &'a Grammar,
+    input: &'a [char],
+    sets: Vec<HashSet<Item>>,
+    active: HashSet<(usize, usize, usize)>,
+    counts: HashMap<(usize, usize, usize), u64>,
+    // Times count has cut a cycle short. A count that cut one depends on
+    // the path taken to it, so isn't kept.
+    cuts: usize,
+}
+
+impl<'a> Forest<'a> {
+    fn new(grammar: &'a Grammar, start: usize, input: &'a [char]) -> Self {
+        let sets = grammar.chart(start, input).into_iter().map(|set| set.into_iter().collect()).collect();
+        Self { grammar, input, sets, active: HashSet::new(), counts: HashMap::new(), cuts: 0 }
+    }
+
+    // Productions of rule that were completed over start..end
+    fn completed(&self, rule: usize, start: usize, end: usize) -> Vec<usize> {
+        self.grammar.by_lhs.get(&rule).map_or(vec![], |ps| {
+            ps.iter()
+                .filter(|p| self.sets[end].contains(&(**p, self.grammar.prods[**p].1.len(), start)))
+                .copied()
+                .collect()
+        })
+    }
+
+    // Ways of splitting start..end between the first dot symbols of p, as
+    // (symbol index, start, end) of the rule references
+    fn splits(&self, p: usize, dot: usize, start: usize, end: usize) -> Vec<Vec<(usize, usize, usize)>> {
+        if dot == 0 {
+            return if start == end { vec![vec![]] } else { vec![] };
+        }
+        match self.grammar.prods[p].1[dot - 1] {
+            Symbol::NonTerm(id) => {
+                let mut out = vec![];
+                for mid in start..=end {
+                    if !self.sets[mid].contains(&(p, dot - 1, start)) || self.completed(id, mid, end).is_empty() {
+                        continue;
+                    }
+                    for mut split in self.splits(p, dot - 1, start, mid) {
+                        split.push((id, mid, end));
+                        out.push(split);
+                    }
+                }
+                out
+            },
+            symbol => {
+                if end > start && self.grammar.scans(symbol, self.input[end - 1]) && self.sets[end - 1].contains(&(p, dot - 1, start)) {
+                    self.splits(p, dot - 1, start, end - 1)
+                } else {
+                    vec![]
+                }
+            },
+        }
+    }
+
+    fn trees(&mut self, rule: usize, start: usize, end: usize) -> Vec<ParseTree> {
+        if !self.active.insert((rule, start, end)) {
+            return vec![];
+        }
+        let mut out = vec![];
+        for p in self.completed(rule, start, end) {
+            for split in self.splits(p, self.grammar.prods[p].1.len(), start, end) {
+                let mut options: Vec<Vec<ParseTree>> = vec![vec![]];
+                for (id, from, to) in split {
+                    let subtrees = self.trees(id, from, to);
+                    let first_synthetic = self.grammar.first_synthetic;
+                    options = options.into_iter()
+                        .flat_map(|prefix| subtrees.iter().map(move |t| {
+                            let mut children = prefix.clone();
+                            // Helper rules are spliced into their parent
+                            if t.rule >= first_synthetic {
+                                children.extend(t.children.iter().cloned());
+                            } else {
+                                children.push(t.clone());
+                            }
+                            children
+                        }))
+                        .collect();
+                }
+                out.extend(options.into_iter().map(|children| ParseTree { rule, start, end, children }));
+            }
+        }
+        self.active.remove(&(rule, start, end));
+        out
+    }
+
+    // Same as trees(..).len() without building them, saturating
+    fn count(&mut self, rule: usize, start: usize, end: usize) -> u64 {
+        if let Some(count) = self.counts.get(&(rule, start, end)) {
+            return *count;
+        }
+        if !self.active.insert((rule, start, end)) {
+            self.cuts += 1;
+            return 0;
+        }
+        let cuts = self.cuts;
+        let mut total = 0u64;
+        for p in self.completed(rule, start, end) {
+            for split in self.splits(p, self.grammar.prods[p].1.len(), start, end) {
+                let ways = split.into_iter().fold(1u64, |acc, (id, from, to)| acc.saturating_mul(self.count(id, from, to)));
+                total = total.saturating_add(ways);
+            }
+        }
+        self.active.remove(&(rule, start, end));
+        if self.cuts == cuts {
+            self.counts.insert((rule, start, end), total);
+        }
+        total
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    UndefinedRule(usize),
+    // The rule recurses in a way compile can't turn into a regex: somewhere
+    // other than the very start or very end of its alternatives, e.g.
+    // 11: 42 31 | 42 11 31, under a * or +, or at the start of some rules
+    // in a mutually recursive group and the end of others. Those usually
+    // need a stack to match.
+    Unsupported(usize),
+    // The regex crate wouldn't build the pattern, e.g. it was too big
+    Regex(String),
+}
+
+// One alternative of a rule in a group of mutually recursive rules, with
+// the rest of the group pulled out to the start or end
+enum Linear {
+    Base(String),
+    Unit(usize),
+    // body followed by a rule of the group
+    Right(String, usize),
+    // A rule of the group followed by body
+    Left(usize, String),
+}
+
+const NOTHING: &str = "[^\\s\\S]";
+
+fn alt_regex(parts: &[String]) -> String {
+    match parts.len() {
+        0 => String::from(NOTHING),
+        1 => parts[0].clone(),
+        _ => format!("(?:{})", parts.join("|")),
+    }
+}
+
+fn star_regex(parts: &[String]) -> String {
+    format!("(?:{})*", parts.join("|"))
+}
+
+// An Automaton compiled down to a single regex, which matches in linear time
+#[derive(Debug, Clone)]
+pub struct CompiledAutomaton {
+    regex: Regex,
+}
+
+impl CompiledAutomaton {
+    pub fn eval(&self, input: &str) -> bool {
+        self.regex.is_match(input)
+    }
+
+    pub fn pattern(&self) -> &str {
+        self.regex.as_str()
+    }
+}
+
+// Recursive descent over a rule body:
+//   alts := seq ('|' seq)*
+//   seq  := term+
+//   term := atom ('?' | '*' | '+' | '{' m '}' | '{' m ',' [n] '}')?
+//   atom := number | '"' chars '"' | '[' ['^'] (c | c '-' c)+ ']' | '(' alts ')'
+// A backslash takes the next character literally, in a literal or a class.
+struct RuleParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl RuleParser {
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.get(self.pos) == Some(&' ') {
+            self.pos += 1;
+        }
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    // The next character, or the one after it if this is a backslash
+    fn literal_char(&mut self) -> Option<char> {
+        let mut c = *self.chars.get(self.pos)?;
+        if c == '\\' {
+            self.pos += 1;
+            c = *self.chars.get(self.pos)?;
+        }
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        self.peek();
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect::<String>().parse().ok()
+    }
+
+    fn alts(&mut self) -> Option<Rule> {
+        let mut alts = vec![self.seq()?];
+        while self.eat('|') {
+            alts.push(self.seq()?);
+        }
+        Some(if alts.len() == 1 { alts.pop()? } else { Rule::Or(alts) })
+    }
+
+    fn seq(&mut self) -> Option<Rule> {
+        let mut terms = vec![];
+        while !matches!(self.peek(), None | Some('|') | Some(')')) {
+            terms.push(self.term()?);
+        }
+        let ids: Option<Vec<usize>> = terms.iter()
+            .map(|term| match term {
+                Rule::Concat(ids) if ids.len() == 1 => Some(ids[0]),
+                _ => None,
+            })
             .collect();
-        Some(Self::Concat(nums))
+        match (ids, terms.len()) {
+            (_, 0) => None,
+            (Some(ids), _) => Some(Rule::Concat(ids)),
+            (None, 1) => terms.pop(),
+            (None, _) => Some(Rule::Seq(terms)),
+        }
     }
 
+    fn term(&mut self) -> Option<Rule> {
+        let atom = self.atom()?;
+        // The operator has to follow the atom directly
+        let (min, max) = match self.chars.get(self.pos) {
+            Some('?') => (0, Some(1)),
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('{') => {
+                self.pos += 1;
+                let min = self.number()?;
+                let max = if !self.eat(',') {
+                    Some(min)
+                } else if self.peek() == Some('}') {
+                    None
+                } else {
+                    Some(self.number()?)
+                };
+                if self.peek() != Some('}') || max.is_some_and(|max| max < min) {
+                    return None;
+                }
+                (min, max)
+            },
+            _ => return Some(atom),
+        };
+        self.pos += 1;
+        Some(Rule::Repeat(Box::new(atom), min, max))
+    }
+
+    fn atom(&mut self) -> Option<Rule> {
+        match self.peek()? {
+            '"' => {
+                self.pos += 1;
+                let mut literal = vec![];
+                while self.chars.get(self.pos) != Some(&'"') {
+                    literal.push(self.literal_char()?);
+                }
+                self.pos += 1;
+                Some(if literal.len() == 1 { Rule::Char(literal[0]) } else { Rule::Str(literal.into_iter().collect()) })
+            },
+            '[' => {
+                self.pos += 1;
+                let negated = self.chars.get(self.pos) == Some(&'^');
+                if negated {
+                    self.pos += 1;
+                }
+                let mut ranges = vec![];
+                loop {
+                    // A ']' straight after the '[' is just a character
+                    if self.chars.get(self.pos) == Some(&']') && !ranges.is_empty() {
+                        self.pos += 1;
+                        break;
+                    }
+                    let lo = self.literal_char()?;
+                    match (self.chars.get(self.pos), self.chars.get(self.pos + 1)) {
+                        (Some('-'), Some(hi)) if *hi != ']' => {
+                            self.pos += 1;
+                            ranges.push((lo, self.literal_char()?));
+                        },
+                        _ => ranges.push((lo, lo)),
+                    }
+                }
+                Some(Rule::Class { negated, ranges })
+            },
+            '(' => {
+                self.pos += 1;
+                let rule = self.alts()?;
+                if self.eat(')') { Some(rule) } else { None }
+            },
+            c if c.is_ascii_digit() => Some(Rule::Concat(vec![self.number()?])),
+            _ => None,
+        }
+    }
+}
+
+impl Rule {
     pub fn from_line(line: &str) -> Option<(usize, Self)> {
         lazy_static! {
             static ref RULE_RE: Regex = Regex::new(r"^(\d+): (.+)$").unwrap();
-            static ref OR_RE: Regex = Regex::new(r"^([\d ]+) \| ([\d ]+)$").unwrap();
-            static ref CONCAT_RE: Regex = Regex::new(r"^([\d ]+)$").unwrap();
-            static ref CHAR_RE: Regex = Regex::new(r#"^"([a-z])"$"#).unwrap();
         };
 
-        if let Some(caps) = RULE_RE.captures(line) {
-            let idx = caps[1].parse::<usize>().ok()?;
-            if let Some(or_caps) = OR_RE.captures(&caps[2]) {
-                Some ((
-                    idx,
-                    Self::Or(vec![
-                        Self::parse_concat(&or_caps[1])?,
-                        Self::parse_concat(&or_caps[2])?,
-                    ]),
-                ))
-            } else if let Some(concat_caps) = CONCAT_RE.captures(&caps[2]) {
-                Some ((
-                    idx,
-                    Self::parse_concat(&concat_caps[1])?,
-                ))
-            } else if let Some(char_caps) = CHAR_RE.captures(&caps[2]) {
-                Some ((
-                    idx,
-                    Self::Char(char_caps[1].parse::<char>().ok()?)
-                ))
-            } else {
-                None
-            }
-        } else {
-            None
+        let caps = RULE_RE.captures(line)?;
+        let idx = caps[1].parse::<usize>().ok()?;
+        let mut parser = RuleParser { chars: caps[2].chars().collect(), pos: 0 };
+        let rule = parser.alts()?;
+        if parser.peek().is_some() {
+            return None;
         }
+        Some((idx, rule))
+    }
+
+    fn refs(&self, out: &mut Vec<usize>) {
+        match self {
+            Rule::Concat(ids) => out.extend(ids.iter().copied()),
+            Rule::Seq(items) | Rule::Or(items) => items.iter().for_each(|item| item.refs(out)),
+            Rule::Repeat(inner, _, _) => inner.refs(out),
+            _ => (),
+        }
+    }
+
+    fn nullable(&self, nullable: &HashSet<usize>) -> bool {
+        match self {
+            Rule::Char(_) | Rule::Class { .. } => false,
+            Rule::Str(s) => s.is_empty(),
+            Rule::Concat(ids) => ids.iter().all(|id| nullable.contains(id)),
+            Rule::Seq(items) => items.iter().all(|item| item.nullable(nullable)),
+            Rule::Or(alts) => alts.iter().any(|alt| alt.nullable(nullable)),
+            Rule::Repeat(inner, min, max) => max.is_none_or(|max| *min <= max) && (*min == 0 || inner.nullable(nullable)),
+        }
+    }
+
+    // Rules eval may try at the same position it started at
+    fn left_refs(&self, nullable: &HashSet<usize>, out: &mut Vec<usize>) {
+        match self {
+            Rule::Concat(ids) => {
+                for id in ids.iter() {
+                    out.push(*id);
+                    if !nullable.contains(id) {
+                        break;
+                    }
+                }
+            },
+            Rule::Seq(items) => {
+                for item in items.iter() {
+                    item.left_refs(nullable, out);
+                    if !item.nullable(nullable) {
+                        break;
+                    }
+                }
+            },
+            Rule::Or(alts) => alts.iter().for_each(|alt| alt.left_refs(nullable, out)),
+            Rule::Repeat(inner, _, _) => inner.left_refs(nullable, out),
+            _ => (),
+        }
+    }
+
+    // Strings matched, given what each rule matches so far
+    fn strings(&self, sets: &HashMap<usize, Strings>) -> Strings {
+        let of = |id: &usize| sets.get(id).cloned().unwrap_or_else(|| Some(BTreeSet::new()));
+        match self {
+            Rule::Char(c) => Some(std::iter::once(c.to_string()).collect()),
+            Rule::Str(s) => Some(std::iter::once(s.clone()).collect()),
+            Rule::Class { negated: true, .. } => None,
+            Rule::Class { ranges, .. } => {
+                let mut out = BTreeSet::new();
+                for c in ranges.iter().flat_map(|(lo, hi)| *lo..=*hi) {
+                    out.insert(c.to_string());
+                    if out.len() > FINITE_LIMIT {
+                        return None;
+                    }
+                }
+                Some(out)
+            },
+            Rule::Concat(ids) => concat_all(ids.iter().map(of)),
+            Rule::Seq(items) => concat_all(items.iter().map(|item| item.strings(sets))),
+            Rule::Or(alts) => alts.iter().try_fold(BTreeSet::new(), |acc, alt| union_strings(&Some(acc), &alt.strings(sets))),
+            Rule::Repeat(_, min, Some(max)) if min > max => Some(BTreeSet::new()),
+            Rule::Repeat(inner, min, max) => {
+                let inner = inner.strings(sets);
+                let only_empty = matches!(&inner, Some(set) if set.iter().all(|s| s.is_empty()));
+                let mut acc: Strings = Some(std::iter::once(String::new()).collect());
+                let mut out = if *min == 0 { acc.clone() } else { Some(BTreeSet::new()) };
+                if max.is_none() && !only_empty {
+                    return None;
+                }
+                // With only "" to repeat, one round past the minimum says it all
+                let rounds = if only_empty { *min.max(&1) } else { max.unwrap() };
+                for count in 1..=rounds {
+                    acc = concat_strings(&acc, &inner);
+                    if count >= *min {
+                        out = union_strings(&out, &acc);
+                    }
+                    if out.is_none() || matches!(&acc, Some(set) if set.is_empty()) {
+                        break;
+                    }
+                }
+                out
+            },
+        }
+    }
+
+    // Whether the rule can go in a sequence or under a repeat without brackets
+    fn is_atom(&self) -> bool {
+        match self {
+            Rule::Concat(ids) => ids.len() == 1,
+            Rule::Seq(_) | Rule::Or(_) => false,
+            _ => true,
+        }
+    }
+}
+
+fn escape_literal(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// The body of the rule, as from_line reads it back
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rule::Char(c) => write!(f, "\"{}\"", escape_literal(&c.to_string())),
+            Rule::Str(s) => write!(f, "\"{}\"", escape_literal(s)),
+            Rule::Class { negated, ranges } => {
+                write!(f, "[{}", if *negated { "^" } else { "" })?;
+                // ']' only reads as itself first, '-' last and '^' anywhere
+                // but first, otherwise they need a backslash
+                let class_char = |c: char, first: bool, last: bool| {
+                    let plain = match c {
+                        '\\' => false,
+                        ']' => first,
+                        '-' => last,
+                        '^' => !first || *negated,
+                        _ => true,
+                    };
+                    if plain { c.to_string() } else { format!("\\{}", c) }
+                };
+                for (i, (lo, hi)) in ranges.iter().enumerate() {
+                    if lo == hi {
+                        write!(f, "{}", class_char(*lo, i == 0, i + 1 == ranges.len()))?;
+                    } else {
+                        write!(f, "{}-{}", class_char(*lo, i == 0, false), class_char(*hi, false, false))?;
+                    }
+                }
+                write!(f, "]")
+            },
+            Rule::Concat(ids) => {
+                let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+                write!(f, "{}", ids.join(" "))
+            },
+            Rule::Seq(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { " " };
+                    if item.is_atom() {
+                        write!(f, "{}{}", sep, item)?;
+                    } else {
+                        write!(f, "{}({})", sep, item)?;
+                    }
+                }
+                Ok(())
+            },
+            Rule::Or(alts) => {
+                for (i, alt) in alts.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { " | " };
+                    if let Rule::Or(_) = alt {
+                        write!(f, "{}({})", sep, alt)?;
+                    } else {
+                        write!(f, "{}{}", sep, alt)?;
+                    }
+                }
+                Ok(())
+            },
+            Rule::Repeat(inner, min, max) => {
+                if inner.is_atom() && !matches!(**inner, Rule::Repeat(..)) {
+                    write!(f, "{}", inner)?;
+                } else {
+                    write!(f, "({})", inner)?;
+                }
+                match (min, max) {
+                    (0, Some(1)) => write!(f, "?"),
+                    (0, None) => write!(f, "*"),
+                    (1, None) => write!(f, "+"),
+                    (min, Some(max)) if min == max => write!(f, "{{{}}}", min),
+                    (min, Some(max)) => write!(f, "{{{},{}}}", min, max),
+                    (min, None) => write!(f, "{{{},}}", min),
+                }
+            },
+        }
+    }
+}
+
+// Matched strings, None when there are more than FINITE_LIMIT of them
+type Strings = Option<BTreeSet<String>>;
+
+const FINITE_LIMIT: usize = 1024;
+
+fn union_strings(a: &Strings, b: &Strings) -> Strings {
+    let (a, b) = (a.as_ref()?, b.as_ref()?);
+    let out: BTreeSet<String> = a.union(b).cloned().collect();
+    if out.len() > FINITE_LIMIT { None } else { Some(out) }
+}
+
+fn concat_strings(a: &Strings, b: &Strings) -> Strings {
+    // Nothing followed by anything is still nothing
+    if matches!(a, Some(set) if set.is_empty()) || matches!(b, Some(set) if set.is_empty()) {
+        return Some(BTreeSet::new());
+    }
+    let (a, b) = (a.as_ref()?, b.as_ref()?);
+    if a.len() * b.len() > FINITE_LIMIT {
+        return None;
+    }
+    Some(a.iter().flat_map(|x| b.iter().map(move |y| format!("{}{}", x, y))).collect())
+}
+
+// Can't stop at the first None, a later empty set still wipes it out
+fn concat_all(parts: impl Iterator<Item = Strings>) -> Strings {
+    let mut acc = Some(std::iter::once(String::new()).collect());
+    for part in parts {
+        acc = concat_strings(&acc, &part);
+    }
+    acc
+}
+
+// Everything Automaton::validate found, each list sorted by rule
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validation {
+    // (rule, the missing rule it refers to)
+    pub undefined: Vec<(usize, usize)>,
+    // Can't be reached from rule 0
+    pub unreachable: Vec<usize>,
+    // Never matches anything, as every alternative recurses forever or
+    // goes through an undefined rule
+    pub non_productive: Vec<usize>,
+    // Can reach itself without consuming input, which eval loops on. Only
+    // recognize can match these.
+    pub left_recursive: Vec<usize>,
+    // Everything a rule matches, for the rules matching finitely many
+    // strings. Rules matching more than FINITE_LIMIT strings are left out.
+    pub finite: BTreeMap<usize, BTreeSet<String>>,
+}
+
+impl Validation {
+    // Safe to hand to eval
+    pub fn is_ok(&self) -> bool {
+        self.undefined.is_empty() && self.non_productive.is_empty() && self.left_recursive.is_empty()
     }
 }
 
@@ -67,35 +814,47 @@ impl Automaton {
         Self { rules: hash_rules }
     }
 
-    fn _eval<'a>(&self, input: &'a[u8], starting_rule: &Rule) -> (bool, Vec<&'a[u8]>) {
-        if input.is_empty() {
-            return (false, vec![]);
+    fn _eval_literal<'a>(input: &'a[u8], literal: &str) -> (bool, Vec<&'a[u8]>) {
+        if input.starts_with(literal.as_bytes()) {
+            (true, vec![&input[literal.len()..]])
+        } else {
+            (false, vec![])
         }
-        match starting_rule {
-            Rule::Char(c) => {
-                if *c == input[0] as char{
-                    (true, vec![&input[1..]])
-                } else {
-                    (false, vec![])
-                }
-            },
-            Rule::Concat(rs) => {
-                let mut current_rems = vec![input];
-                for r in rs.iter() {
-                    let mut next_rems: HashSet<&[u8]> = HashSet::new();
-                    for current_rem in current_rems.iter() {
-                        let (_passed, mut _rem_vec) = self._eval(current_rem, &self.rules.get(r).unwrap());
-                        next_rems.extend(_rem_vec);
-                    }
+    }
 
-                    if next_rems.is_empty() {
-                        return (false, vec![])
-                    }
-                    current_rems = next_rems.drain().collect();
-                }
+    fn _eval_seq<'a, 'r>(&self, input: &'a[u8], rs: impl Iterator<Item = &'r Rule>) -> (bool, Vec<&'a[u8]>) {
+        let mut current_rems = vec![input];
+        for r in rs {
+            let mut next_rems: HashSet<&[u8]> = HashSet::new();
+            for current_rem in current_rems.iter() {
+                let (_passed, _rem_vec) = self._eval(current_rem, r);
+                next_rems.extend(_rem_vec);
+            }
 
-                (true, current_rems)
+            if next_rems.is_empty() {
+                return (false, vec![])
+            }
+            current_rems = next_rems.drain().collect();
+        }
+
+        (true, current_rems)
+    }
+
+    fn _eval<'a>(&self, input: &'a[u8], starting_rule: &Rule) -> (bool, Vec<&'a[u8]>) {
+        match starting_rule {
+            Rule::Char(c) => Self::_eval_literal(input, c.encode_utf8(&mut [0; 4])),
+            Rule::Str(s) => Self::_eval_literal(input, s),
+            Rule::Class { negated, ranges } => {
+                let first = (1..=input.len().min(4))
+                    .find_map(|len| std::str::from_utf8(&input[..len]).ok())
+                    .and_then(|s| s.chars().next());
+                match first {
+                    Some(c) if class_matches(*negated, ranges, c) => (true, vec![&input[c.len_utf8()..]]),
+                    _ => (false, vec![]),
+                }
             },
+            Rule::Concat(rs) => self._eval_seq(input, rs.iter().map(|r| self.rules.get(r).unwrap())),
+            Rule::Seq(rs) => self._eval_seq(input, rs.iter()),
             Rule::Or(rs) => {
                 let mut rems = vec![];
                 for r in rs.iter() {
@@ -103,7 +862,26 @@ impl Automaton {
                     rems.append(&mut rem);
                 }
                 (!rems.is_empty(), rems)
-            }
+            },
+            Rule::Repeat(r, min, max) => {
+                let mut rems = vec![];
+                let mut seen = HashSet::new();
+                let mut current_rems: HashSet<&[u8]> = [input].iter().copied().collect();
+                let mut count = 0;
+                loop {
+                    if count >= *min {
+                        // Once past the minimum, a remainder seen before adds nothing
+                        current_rems.retain(|rem| seen.insert(rem.len()));
+                        rems.extend(current_rems.iter().copied());
+                    }
+                    if current_rems.is_empty() || Some(count) == *max {
+                        break;
+                    }
+                    current_rems = current_rems.iter().flat_map(|rem| self._eval(rem, r).1).collect();
+                    count += 1;
+                }
+                (!rems.is_empty(), rems)
+            },
         }
     }
 
@@ -116,15 +894,361 @@ impl Automaton {
         return rems.iter().any(|l| l.is_empty());
     }
 
+    fn concat_regex(&self, ids: &[usize], memo: &mut HashMap<usize, String>) -> Result<String, CompileError> {
+        ids.iter().map(|id| self.rule_regex(*id, memo)).collect()
+    }
+
+    // Regex for anything that isn't a plain reference to a rule
+    fn item_regex(&self, rule: &Rule, memo: &mut HashMap<usize, String>) -> Result<String, CompileError> {
+        Ok(match rule {
+            Rule::Char(c) => regex::escape(&c.to_string()),
+            Rule::Str(s) => regex::escape(s),
+            Rule::Class { negated, ranges } => {
+                let body: String = ranges.iter()
+                    .map(|(lo, hi)| format!("{}-{}", regex::escape(&lo.to_string()), regex::escape(&hi.to_string())))
+                    .collect();
+                format!("[{}{}]", if *negated { "^" } else { "" }, body)
+            },
+            Rule::Concat(ids) => self.concat_regex(ids, memo)?,
+            Rule::Seq(items) => items.iter().map(|item| self.item_regex(item, memo)).collect::<Result<String, _>>()?,
+            Rule::Or(alts) if alts.is_empty() => String::from(NOTHING),
+            Rule::Or(alts) => {
+                let parts = alts.iter().map(|alt| self.item_regex(alt, memo)).collect::<Result<Vec<_>, _>>()?;
+                format!("(?:{})", parts.join("|"))
+            },
+            Rule::Repeat(inner, min, max) => {
+                let inner = self.item_regex(inner, memo)?;
+                match max {
+                    Some(max) => format!("(?:{}){{{},{}}}", inner, min, max),
+                    None => format!("(?:{}){{{},}}", inner, min),
+                }
+            },
+        })
+    }
+
+    // Every rule reachable from id, not counting id itself unless it recurses
+    fn reachable(&self, id: usize) -> HashSet<usize> {
+        let mut seen = HashSet::new();
+        let mut todo = vec![id];
+        while let Some(next) = todo.pop() {
+            if let Some(rule) = self.rules.get(&next) {
+                let mut refs = vec![];
+                rule.refs(&mut refs);
+                todo.extend(refs.into_iter().filter(|r| seen.insert(*r)));
+            }
+        }
+        seen
+    }
+
+    // The alternatives of rule, which belongs to group, with each use of
+    // the group at the start or end of one
+    fn linear(&self, owner: usize, rule: &Rule, group: &HashSet<usize>, memo: &mut HashMap<usize, String>) -> Result<Vec<Linear>, CompileError> {
+        let mentions = |rule: &Rule| {
+            let mut refs = vec![];
+            rule.refs(&mut refs);
+            refs.iter().any(|r| group.contains(r))
+        };
+        if !mentions(rule) {
+            return Ok(vec![Linear::Base(self.item_regex(rule, memo)?)]);
+        }
+        match rule {
+            Rule::Concat(ids) if ids.len() == 1 => Ok(vec![Linear::Unit(ids[0])]),
+            Rule::Concat(ids) => {
+                let items: Vec<Rule> = ids.iter().map(|id| Rule::Concat(vec![*id])).collect();
+                self.linear(owner, &Rule::Seq(items), group, memo)
+            },
+            Rule::Seq(items) if items.len() == 1 => self.linear(owner, &items[0], group, memo),
+            Rule::Seq(items) => {
+                let (first, last) = (&items[0], &items[items.len() - 1]);
+                let middle = &items[1..items.len() - 1];
+                if middle.iter().any(mentions) {
+                    return Err(CompileError::Unsupported(owner));
+                }
+                let middle = middle.iter().map(|item| self.item_regex(item, memo)).collect::<Result<String, _>>()?;
+                let heads = self.linear(owner, first, group, memo)?;
+                let tails = self.linear(owner, last, group, memo)?;
+
+                let mut out = vec![];
+                for head in heads.iter() {
+                    let (head_id, head_body) = match head {
+                        Linear::Base(body) => (None, body.as_str()),
+                        Linear::Unit(id) => (Some(*id), ""),
+                        Linear::Left(id, body) => (Some(*id), body.as_str()),
+                        Linear::Right(..) => return Err(CompileError::Unsupported(owner)),
+                    };
+                    for tail in tails.iter() {
+                        let (tail_body, tail_id) = match tail {
+                            Linear::Base(body) => (body.as_str(), None),
+                            Linear::Unit(id) => ("", Some(*id)),
+                            Linear::Right(body, id) => (body.as_str(), Some(*id)),
+                            Linear::Left(..) => return Err(CompileError::Unsupported(owner)),
+                        };
+                        let body = format!("{}{}{}", head_body, middle, tail_body);
+                        out.push(match (head_id, tail_id) {
+                            (None, None) => Linear::Base(body),
+                            (Some(id), None) => Linear::Left(id, body),
+                            (None, Some(id)) => Linear::Right(body, id),
+                            (Some(_), Some(_)) => return Err(CompileError::Unsupported(owner)),
+                        });
+                    }
+                }
+                Ok(out)
+            },
+            Rule::Or(alts) => {
+                let mut out = vec![];
+                for alt in alts.iter() {
+                    out.extend(self.linear(owner, alt, group, memo)?);
+                }
+                Ok(out)
+            },
+            // x? is just x | ""
+            Rule::Repeat(inner, min, Some(1)) if *min <= 1 => {
+                let mut out = self.linear(owner, inner, group, memo)?;
+                if *min == 0 {
+                    out.push(Linear::Base(String::new()));
+                }
+                Ok(out)
+            },
+            _ => Err(CompileError::Unsupported(owner)),
+        }
+    }
+
+    // Regex for a rule. Rules that recurse are solved together with the
+    // rest of their group, as equations in the group's rules. A lone rule
+    // may recurse at either end of its alternatives: R: a R | R b | c is
+    // just a*cb*. A bigger group has to keep to one end throughout, e.g.
+    // 1: "a" 2 | "c" with 2: "b" 1 is (ab)*c.
+    fn rule_regex(&self, id: usize, memo: &mut HashMap<usize, String>) -> Result<String, CompileError> {
+        if let Some(regex) = memo.get(&id) {
+            return Ok(regex.clone());
+        }
+        let rule = self.rules.get(&id).ok_or(CompileError::UndefinedRule(id))?;
+
+        let reach = self.reachable(id);
+        if !reach.contains(&id) {
+            let regex = self.item_regex(rule, memo)?;
+            memo.insert(id, regex.clone());
+            return Ok(regex);
+        }
+        let group: HashSet<usize> = reach.iter().copied().filter(|r| self.reachable(*r).contains(&id)).collect();
+        let mut members: Vec<usize> = group.iter().copied().collect();
+        members.sort_unstable();
+
+        let mut alts = vec![];
+        for member in members.iter() {
+            alts.push(self.linear(*member, &self.rules[member], &group, memo)?);
+        }
+        let any_left = alts.iter().flatten().any(|alt| matches!(alt, Linear::Left(..)));
+        let any_right = alts.iter().flatten().any(|alt| matches!(alt, Linear::Right(..)));
+
+        if members.len() == 1 {
+            let (mut prefixes, mut bases, mut suffixes) = (vec![], vec![], vec![]);
+            for alt in alts.pop().unwrap() {
+                match alt {
+                    Linear::Base(body) => bases.push(body),
+                    Linear::Right(body, _) => prefixes.push(body),
+                    Linear::Left(_, body) => suffixes.push(body),
+                    // R: R matches nothing new
+                    Linear::Unit(_) => (),
+                }
+            }
+            let mut regex = alt_regex(&bases);
+            if !prefixes.is_empty() {
+                regex = format!("{}{}", star_regex(&prefixes), regex);
+            }
+            if !suffixes.is_empty() {
+                regex = format!("{}{}", regex, star_regex(&suffixes));
+            }
+            memo.insert(id, regex.clone());
+            return Ok(regex);
+        }
+        if any_left && any_right {
+            return Err(CompileError::Unsupported(id));
+        }
+
+        // Each rule as the union of its bases and coefficient-then-rule
+        // terms, or rule-then-coefficient if the group is left linear.
+        // Eliminating one rule at a time leaves just the bases.
+        let join = |coefficient: &str, rest: &str| {
+            if any_left { format!("{}{}", rest, coefficient) } else { format!("{}{}", coefficient, rest) }
+        };
+        let mut equations = vec![];
+        for (member, member_alts) in members.iter().zip(alts) {
+            let (mut terms, mut bases) = (BTreeMap::new(), vec![]);
+            for alt in member_alts {
+                match alt {
+                    Linear::Base(body) => bases.push(body),
+                    Linear::Unit(other) if other == *member => (),
+                    Linear::Unit(other) => terms.entry(other).or_insert_with(Vec::new).push(String::new()),
+                    Linear::Right(body, other) | Linear::Left(other, body) => terms.entry(other).or_insert_with(Vec::new).push(body),
+                }
+            }
+            equations.push((terms, bases));
+        }
+
+        for k in 0..members.len() {
+            let (mut terms, mut bases) = std::mem::take(&mut equations[k]);
+            // R = c R | rest, so R = c* rest
+            if let Some(own) = terms.remove(&members[k]) {
+                let own = star_regex(&own);
+                for parts in terms.values_mut() {
+                    *parts = vec![join(&own, &alt_regex(parts))];
+                }
+                if !bases.is_empty() {
+                    bases = vec![join(&own, &alt_regex(&bases))];
+                }
+            }
+            for (other_terms, other_bases) in equations.iter_mut() {
+                let coefficient = match other_terms.remove(&members[k]) {
+                    Some(parts) => alt_regex(&parts),
+                    None => continue,
+                };
+                for (next, parts) in terms.iter() {
+                    other_terms.entry(*next).or_insert_with(Vec::new).push(join(&coefficient, &alt_regex(parts)));
+                }
+                if !bases.is_empty() {
+                    other_bases.push(join(&coefficient, &alt_regex(&bases)));
+                }
+            }
+            equations[k] = (terms, bases);
+        }
+
+        for (member, (_, bases)) in members.iter().zip(equations) {
+            memo.insert(*member, alt_regex(&bases));
+        }
+        Ok(memo[&id].clone())
+    }
+
+    // Compiles rule 0 into a regex, if compile can handle its recursion
+    pub fn compile(&self) -> Result<CompiledAutomaton, CompileError> {
+        let body = self.rule_regex(0, &mut HashMap::new())?;
+        let regex = regex::RegexBuilder::new(&format!("^{}$", body))
+            .size_limit(1 << 28)
+            .build()
+            .map_err(|e| CompileError::Regex(e.to_string()))?;
+        Ok(CompiledAutomaton { regex })
+    }
+
+    // Replaces or adds rules
+    pub fn patch(&mut self, rules: &[(usize, Rule)]) {
+        for (id, rule) in rules.iter() {
+            self.rules.insert(*id, rule.clone());
+        }
+    }
+
+    // Matches with a general context free recognizer, so any recursion is
+    // fine, unlike eval which loops forever on left recursion.
+    pub fn recognize(&self, input: &str) -> bool {
+        let input: Vec<char> = input.chars().collect();
+        Grammar::from_rules(&self.rules).accepts(0, &input)
+    }
+
+    // Every derivation of the message from rule 0, empty when it doesn't match
+    pub fn parse(&self, input: &str) -> Vec<ParseTree> {
+        let input: Vec<char> = input.chars().collect();
+        let grammar = Grammar::from_rules(&self.rules);
+        Forest::new(&grammar, 0, &input).trees(0, 0, input.len())
+    }
+
+    // Number of derivations, without listing them
+    pub fn derivations(&self, input: &str) -> u64 {
+        let input: Vec<char> = input.chars().collect();
+        let grammar = Grammar::from_rules(&self.rules);
+        Forest::new(&grammar, 0, &input).count(0, 0, input.len())
+    }
+
+    pub fn is_ambiguous(&self, input: &str) -> bool {
+        self.derivations(input) > 1
+    }
+
+    pub fn validate(&self) -> Validation {
+        let mut ids: Vec<usize> = self.rules.keys().copied().collect();
+        ids.sort_unstable();
+        let refs = |id: &usize| {
+            let mut out = vec![];
+            self.rules[id].refs(&mut out);
+            out
+        };
+
+        let mut undefined = vec![];
+        for id in ids.iter() {
+            for r in refs(id) {
+                if !self.rules.contains_key(&r) && !undefined.contains(&(*id, r)) {
+                    undefined.push((*id, r));
+                }
+            }
+        }
+
+        let mut reached = HashSet::new();
+        let mut todo = vec![0];
+        while let Some(id) = todo.pop() {
+            if self.rules.contains_key(&id) && reached.insert(id) {
+                todo.extend(refs(&id));
+            }
+        }
+        let unreachable = ids.iter().filter(|id| !reached.contains(id)).copied().collect();
+
+        // Grow what every rule matches until nothing changes. Sets only get
+        // bigger, and give up at FINITE_LIMIT, so this settles.
+        let mut sets: HashMap<usize, Strings> = ids.iter().map(|id| (*id, Some(BTreeSet::new()))).collect();
+        loop {
+            let next: HashMap<usize, Strings> = ids.iter().map(|id| (*id, self.rules[id].strings(&sets))).collect();
+            if next == sets {
+                break;
+            }
+            sets = next;
+        }
+        let non_productive = ids.iter()
+            .filter(|id| matches!(&sets[id], Some(set) if set.is_empty()))
+            .copied()
+            .collect();
+
+        let mut nullable = HashSet::new();
+        loop {
+            let before = nullable.len();
+            for id in ids.iter() {
+                if self.rules[id].nullable(&nullable) {
+                    nullable.insert(*id);
+                }
+            }
+            if nullable.len() == before {
+                break;
+            }
+        }
+        let left_refs = |id: &usize| {
+            let mut out = vec![];
+            if let Some(rule) = self.rules.get(id) {
+                rule.left_refs(&nullable, &mut out);
+            }
+            out
+        };
+        let left_recursive = ids.iter()
+            .filter(|id| {
+                let mut seen = HashSet::new();
+                let mut todo = left_refs(id);
+                while let Some(next) = todo.pop() {
+                    if next == **id {
+                        return true;
+                    }
+                    if seen.insert(next) {
+                        todo.extend(left_refs(&next));
+                    }
+                }
+                false
+            })
+            .copied()
+            .collect();
+
+        let finite = sets.into_iter().filter_map(|(id, set)| Some((id, set?))).collect();
+
+        Validation { undefined, unreachable, non_productive, left_recursive, finite }
+    }
+
     pub fn part_2_hack(&mut self) {
-        self.rules.insert(8, Rule::Or(vec![
-            Rule::Concat(vec![42]),
-            Rule::Concat(vec![42, 8]),
-        ]));
-        self.rules.insert(11, Rule::Or(vec![
-            Rule::Concat(vec![42, 31]),
-            Rule::Concat(vec![42, 11, 31]),
-        ]));
+        self.patch(&[
+            Rule::from_line("8: 42 | 42 8").unwrap(),
+            Rule::from_line("11: 42 31 | 42 11 31").unwrap(),
+        ]);
     }
 }
 
@@ -158,6 +1282,264 @@ mod tests {
         println!("Part 2: {}", count_2);
     }
 
+    fn read_rules(filename: &str) -> (Vec<(usize, Rule)>, Vec<String>) {
+        let input = std::fs::read_to_string(filename).unwrap();
+        let mut lines = input.lines();
+        let mut rules = vec![];
+        while let Some(rule) = Rule::from_line(lines.next().unwrap()) {
+            rules.push(rule);
+        }
+        (rules, lines.map(String::from).collect())
+    }
+
+    #[test]
+    fn compile() {
+        for filename in ["res/19/input_simple.txt", "res/19/input_part_2.txt", "res/19/input.txt"].iter() {
+            let (rules, messages) = read_rules(filename);
+            let automaton = Automaton::from_rules(&rules);
+            let compiled = automaton.compile().unwrap();
+            for message in messages.iter() {
+                assert_eq!(compiled.eval(message), automaton.eval(message), "{}", message);
+            }
+
+            if !automaton.rules.contains_key(&42) {
+                continue;
+            }
+
+            // 8: 42 | 42 8 is still regular, 11: 42 31 | 42 11 31 isn't
+            let mut automaton = Automaton::from_rules(&rules);
+            automaton.part_2_hack();
+            assert_eq!(automaton.compile().unwrap_err(), CompileError::Unsupported(11));
+            automaton.rules.insert(11, Rule::Concat(vec![42, 31]));
+            let compiled = automaton.compile().unwrap();
+            for message in messages.iter() {
+                assert_eq!(compiled.eval(message), automaton.eval(message), "{}", message);
+            }
+        }
+
+        // Recursion at both ends, R: a R | R b | c
+        let rules = vec![
+            (0, Rule::Or(vec![Rule::Concat(vec![1, 0]), Rule::Concat(vec![0, 2]), Rule::Concat(vec![3])])),
+            (1, Rule::Char('a')),
+            (2, Rule::Char('b')),
+            (3, Rule::Char('c')),
+        ];
+        let compiled = Automaton::from_rules(&rules).compile().unwrap();
+        assert!(compiled.eval("c") && compiled.eval("aacbbb") && compiled.eval("cb"));
+        assert!(!compiled.eval("acab") && !compiled.eval("ab") && !compiled.eval(""));
+
+        let rules = vec![(0, Rule::Concat(vec![1, 4])), (1, Rule::Char('a'))];
+        assert_eq!(Automaton::from_rules(&rules).compile().unwrap_err(), CompileError::UndefinedRule(4));
+
+        // Recursion through sequences and groups of rules, checked against
+        // the Earley recognizer
+        let grammars: [(&[&str], &[&str], &[&str]); 5] = [
+            (&["0: 1", "1: \"a\" 2 | \"c\"", "2: \"b\" 1"], &["c", "abc", "ababc"], &["", "ab", "abac", "cab"]),
+            (&["0: \"x\" 0 | \"y\""], &["y", "xxy"], &["", "x", "yx"]),
+            (&["0: 1 \"!\"", "1: 2 \"a\" | \"s\"", "2: 1 \"b\" | 1"], &["s!", "sa!", "saba!", "sbaa!"], &["a!", "s", "sb!"]),
+            (&["0: \"<\" (0 | 1)", "1: \">\" 0 | \".\""], &["<.", "<<.", "<><.", "<><<."], &["<", "<>.", "."]),
+            (&["0: 1 | 2", "1: \"a\"+ 2 | \"x\"", "2: [bc] 1?"], &["x", "b", "cab", "aab", "abaabx"], &["", "a", "bc", "ax"]),
+        ];
+        for (lines, yes, no) in grammars.iter() {
+            let automaton = Automaton::from_rules(&lines.iter().map(|l| Rule::from_line(l).unwrap()).collect());
+            let compiled = automaton.compile().unwrap();
+            for message in yes.iter().chain(no.iter()) {
+                assert_eq!(compiled.eval(message), yes.contains(message), "{} in {:?}", message, lines);
+                assert_eq!(automaton.recognize(message), yes.contains(message), "{} in {:?}", message, lines);
+            }
+        }
+
+        // Recursion in the middle, under a repeat, or at both ends of a group
+        for lines in [&["0: \"a\" 0 \"b\" | \"c\""][..], &["0: (\"a\" 0)+ | \"b\""], &["0: \"a\" 1", "1: 0 \"b\" | \"c\""]].iter() {
+            let automaton = Automaton::from_rules(&lines.iter().map(|l| Rule::from_line(l).unwrap()).collect());
+            assert_eq!(automaton.compile().unwrap_err(), CompileError::Unsupported(0), "{:?}", lines);
+        }
+
+        // Too big for the regex crate is its own error
+        let rules = vec![Rule::from_line("0: (([a-z]{1000}){1000}){1000}").unwrap()];
+        assert!(matches!(Automaton::from_rules(&rules).compile(), Err(CompileError::Regex(_))));
+    }
+
+    #[test]
+    fn recognize() {
+        for filename in ["res/19/input_simple.txt", "res/19/input_part_2.txt"].iter() {
+            let (rules, messages) = read_rules(filename);
+            let automaton = Automaton::from_rules(&rules);
+            for message in messages.iter() {
+                assert_eq!(automaton.recognize(message), automaton.eval(message), "{}", message);
+            }
+        }
+
+        let (rules, messages) = read_rules("res/19/input_part_2.txt");
+        let mut automaton = Automaton::from_rules(&rules);
+        automaton.part_2_hack();
+        assert_eq!(messages.iter().filter(|m| automaton.recognize(m)).count(), 12);
+
+        // Same loops written left recursive, which eval can't cope with
+        automaton.patch(&[
+            Rule::from_line("8: 8 42 | 42").unwrap(),
+            Rule::from_line("11: 42 31 | 42 11 31").unwrap(),
+        ]);
+        assert_eq!(messages.iter().filter(|m| automaton.recognize(m)).count(), 12);
+
+        // a b*, and a grammar with an undefined rule
+        let rules = vec![
+            (0, Rule::Or(vec![Rule::Concat(vec![0, 1]), Rule::Concat(vec![2])])),
+            (1, Rule::Char('b')),
+            (2, Rule::Char('a')),
+            (3, Rule::Concat(vec![2, 7])),
+        ];
+        let automaton = Automaton::from_rules(&rules);
+        assert!(automaton.recognize("a") && automaton.recognize("abbbb"));
+        assert!(!automaton.recognize("") && !automaton.recognize("ba") && !automaton.recognize("abab"));
+        let mut automaton = automaton;
+        automaton.patch(&[(0, Rule::Concat(vec![3]))]);
+        assert!(!automaton.recognize("a"));
+    }
+
+    #[test]
+    fn parse_trees() {
+        let (rules, messages) = read_rules("res/19/input_simple.txt");
+        let automaton = Automaton::from_rules(&rules);
+        for message in messages.iter() {
+            let trees = automaton.parse(message);
+            assert_eq!(trees.len() as u64, automaton.derivations(message));
+            assert_eq!(trees.is_empty(), !automaton.eval(message));
+        }
+
+        let trees = automaton.parse("ababbb");
+        assert_eq!(trees.len(), 1);
+        assert!(!automaton.is_ambiguous("ababbb"));
+        assert_eq!(trees[0].spans()[..3], [(0, 0, 6), (4, 0, 1), (1, 1, 5)]);
+        println!("{}", trees[0].render("ababbb"));
+
+        // a+ split into two non-empty halves, one way per cut
+        let rules = vec![
+            (0, Rule::Concat(vec![1, 1])),
+            (1, Rule::Or(vec![Rule::Concat(vec![2]), Rule::Concat(vec![1, 2])])),
+            (2, Rule::Char('a')),
+        ];
+        let automaton = Automaton::from_rules(&rules);
+        assert_eq!(automaton.derivations("aaaaa"), 4);
+        assert!(automaton.is_ambiguous("aaa") && !automaton.is_ambiguous("aa"));
+        let trees = automaton.parse("aaa");
+        assert_eq!(trees.len(), 2);
+        assert_eq!(trees[0].render("aaa"), "(0 (1 (2 \"a\")) (1 (1 (2 \"a\")) (2 \"a\")))");
+        assert!(automaton.parse("").is_empty());
+
+        // The unit cycle 1 -> 1 is not counted
+        let mut automaton = automaton;
+        automaton.patch(&[(1, Rule::Or(vec![Rule::Concat(vec![2]), Rule::Concat(vec![1])]))]);
+        assert_eq!(automaton.derivations("aa"), 1);
+
+        // Two rules reaching each other, whichever comes first
+        let rules = ["0: 1 | 2", "1: 2 | 3", "2: 1 | 3", "3: \"a\""].iter().map(|l| Rule::from_line(l).unwrap()).collect();
+        let automaton = Automaton::from_rules(&rules);
+        assert_eq!(automaton.parse("a").len(), 4);
+        assert_eq!(automaton.derivations("a"), 4);
+    }
+
+    #[test]
+    fn rich_rules() {
+        let lines = [
+            "0: 1 2 | 3 4 | 5",
+            "1: \"ab\"+ 2? [x-z]{2}",
+            "2: \"-\" | \"+\"",
+            "3: [^a-c]* \"end\"",
+            "4: (1 | 2 3)* [a_0-9]{1,3} [^]]{2,}",
+            "5: ((1 2) 3)? \"\" []-]",
+        ];
+        for line in lines.iter() {
+            let (idx, rule) = Rule::from_line(line).unwrap();
+            assert_eq!(format!("{}: {}", idx, rule), *line);
+        }
+        assert_eq!(Rule::from_line("7: \"q\"").unwrap().1, Rule::Char('q'));
+        assert_eq!(Rule::from_line("7: 1 | 2 | 3 4").unwrap().1, Rule::Or(vec![
+            Rule::Concat(vec![1]), Rule::Concat(vec![2]), Rule::Concat(vec![3, 4]),
+        ]));
+        // Backslashes and awkward class members survive the round trip
+        let awkward = [
+            Rule::Class { negated: false, ranges: vec![('a', 'a'), ('-', '-'), ('z', 'z')] },
+            Rule::Class { negated: false, ranges: vec![('a', 'a'), (']', ']')] },
+            Rule::Class { negated: false, ranges: vec![('^', '^'), ('!', '-'), (']', '}'), ('\\', '\\')] },
+            Rule::Class { negated: true, ranges: vec![('^', '^'), ('-', '-')] },
+            Rule::Str(String::from("say \"hi\" \\o/")),
+            Rule::Char('"'),
+            Rule::Char('\\'),
+        ];
+        for rule in awkward.iter() {
+            let line = format!("7: {}", rule);
+            assert_eq!(Rule::from_line(&line), Some((7, rule.clone())), "{}", line);
+        }
+        assert_eq!(format!("{}", awkward[0]), "[a\\-z]");
+        assert_eq!(Rule::from_line("7: \"\\\"\" [\\]\\-]").unwrap().1, Rule::Seq(vec![
+            Rule::Char('"'), Rule::Class { negated: false, ranges: vec![(']', ']'), ('-', '-')] },
+        ]));
+
+        for bad in ["7: ", "7: 1 |", "7: (1 2", "7: 1)", "7: []", "7: \"a", "7: 1{2", "7: +", "7: 1{3,1}", "7: \"a\\\""].iter() {
+            assert_eq!(Rule::from_line(bad), None, "{}", bad);
+        }
+
+        let rules: Vec<(usize, Rule)> = [
+            "0: 1 | 3",
+            "1: \"ab\"+ 2? [x-z]{2}",
+            "2: \"-\" | \"+\"",
+            "3: [^a-c]* \"end\"",
+        ].iter().map(|line| Rule::from_line(line).unwrap()).collect();
+        let automaton = Automaton::from_rules(&rules);
+        let compiled = automaton.compile().unwrap();
+        let cases = [
+            ("abab-xy", true), ("abxz", true), ("ab+xyz", false), ("zzend", true),
+            ("end", true), ("aend", false), ("ab", false), ("", false), ("abxy", true),
+        ];
+        for (message, expected) in cases.iter() {
+            assert_eq!(automaton.eval(message), *expected, "{}", message);
+            assert_eq!(automaton.recognize(message), *expected, "{}", message);
+            assert_eq!(compiled.eval(message), *expected, "{}", message);
+        }
+
+        // Helper rules for the repeats don't show up in the tree
+        let trees = automaton.parse("ab-xy");
+        assert_eq!(trees.len(), 1);
+        assert_eq!(trees[0].spans(), vec![(0, 0, 5), (1, 0, 5), (2, 2, 3)]);
+    }
+
+    #[test]
+    fn validate() {
+        let (rules, _) = read_rules("res/19/input_simple.txt");
+        let validation = Automaton::from_rules(&rules).validate();
+        assert!(validation.is_ok() && validation.unreachable.is_empty());
+        assert_eq!(validation.finite[&4], ["a"].iter().map(|s| s.to_string()).collect());
+        assert_eq!(validation.finite[&0].len(), 8);
+        assert!(validation.finite[&0].contains("ababbb"));
+
+        let (rules, _) = read_rules("res/19/input.txt");
+        let mut automaton = Automaton::from_rules(&rules);
+        automaton.part_2_hack();
+        let validation = automaton.validate();
+        assert!(validation.is_ok());
+        assert!(!validation.finite.contains_key(&8) && validation.finite[&42].len() == 128);
+        automaton.patch(&[Rule::from_line("8: 8 42 | 42").unwrap()]);
+        assert_eq!(automaton.validate().left_recursive, vec![8]);
+
+        let rules: Vec<(usize, Rule)> = [
+            "0: 1 2 | 3",
+            "1: \"a\"",
+            "2: 9 | 2",
+            "3: 5? 3 \"b\"",
+            "4: \"c\"+ | [x-z] [^x]",
+            "5: \"d\"{0,2}",
+        ].iter().map(|line| Rule::from_line(line).unwrap()).collect();
+        let validation = Automaton::from_rules(&rules).validate();
+        assert!(!validation.is_ok());
+        assert_eq!(validation.undefined, vec![(2, 9)]);
+        assert_eq!(validation.unreachable, vec![4]);
+        assert_eq!(validation.non_productive, vec![0, 2, 3]);
+        assert_eq!(validation.left_recursive, vec![2, 3]);
+        assert_eq!(validation.finite.keys().copied().collect::<Vec<_>>(), vec![0, 1, 2, 3, 5]);
+        assert_eq!(validation.finite[&5], ["", "d", "dd"].iter().map(|s| s.to_string()).collect());
+    }
+
     #[test]
     fn test_it() {
         drive("res/19/input.txt");
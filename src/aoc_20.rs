@@ -2,93 +2,165 @@ use regex::Regex;
 use lazy_static::lazy_static;
 use std::collections::{HashSet, HashMap};
 
+// The eight symmetries of a rectangular grid, i.e. the dihedral group D4.
+// Rotations are clockwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum D4 {
+    Identity,
+    Rot90,
+    Rot180,
+    Rot270,
+    // Mirror left to right
+    FlipH,
+    // Mirror top to bottom
+    FlipV,
+    // Mirror along the main diagonal
+    Transpose,
+    // Mirror along the other diagonal
+    AntiTranspose,
+}
+
+impl D4 {
+    pub const ALL: [D4; 8] = [
+        D4::Identity, D4::Rot90, D4::Rot180, D4::Rot270,
+        D4::FlipH, D4::FlipV, D4::Transpose, D4::AntiTranspose,
+    ];
+
+    pub fn all() -> impl Iterator<Item = D4> {
+        Self::ALL.iter().copied()
+    }
+
+    // Action on (row, col) coordinates measured from the center of the grid.
+    // Every symmetry is a signed permutation matrix.
+    fn matrix(self) -> [[i64; 2]; 2] {
+        match self {
+            Self::Identity => [[1, 0], [0, 1]],
+            Self::Rot90 => [[0, 1], [-1, 0]],
+            Self::Rot180 => [[-1, 0], [0, -1]],
+            Self::Rot270 => [[0, -1], [1, 0]],
+            Self::FlipH => [[1, 0], [0, -1]],
+            Self::FlipV => [[-1, 0], [0, 1]],
+            Self::Transpose => [[0, 1], [1, 0]],
+            Self::AntiTranspose => [[0, -1], [-1, 0]],
+        }
+    }
+
+    fn from_matrix(m: [[i64; 2]; 2]) -> Self {
+        Self::all().find(|t| t.matrix() == m).unwrap()
+    }
+
+    // The transform that does `other` first, then `self`
+    pub fn compose(self, other: Self) -> Self {
+        let (a, b) = (self.matrix(), other.matrix());
+        let mut m = [[0; 2]; 2];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, val) in row.iter_mut().enumerate() {
+                *val = a[i][0] * b[0][j] + a[i][1] * b[1][j];
+            }
+        }
+        Self::from_matrix(m)
+    }
+
+    pub fn inverse(self) -> Self {
+        // Permutation matrices are orthogonal, so the transpose is the inverse
+        let m = self.matrix();
+        Self::from_matrix([[m[0][0], m[1][0]], [m[0][1], m[1][1]]])
+    }
+
+    pub fn swaps_axes(self) -> bool {
+        self.matrix()[0][0] == 0
+    }
+
+    // Dimensions (rows, cols) of a grid after the transform
+    pub fn apply_dims(self, (rows, cols): (usize, usize)) -> (usize, usize) {
+        if self.swaps_axes() {
+            (cols, rows)
+        } else {
+            (rows, cols)
+        }
+    }
+
+    // Where the cell at (i, j) of a rows x cols grid ends up after the transform
+    pub fn apply_coords(self, (i, j): (usize, usize), (rows, cols): (usize, usize)) -> (usize, usize) {
+        // Doubled so the center of the grid lands on an integer
+        let x = 2 * i as i64 - (rows as i64 - 1);
+        let y = 2 * j as i64 - (cols as i64 - 1);
+        let m = self.matrix();
+        let new_x = m[0][0] * x + m[0][1] * y;
+        let new_y = m[1][0] * x + m[1][1] * y;
+
+        let (new_rows, new_cols) = self.apply_dims((rows, cols));
+        (
+            ((new_x + new_rows as i64 - 1) / 2) as usize,
+            ((new_y + new_cols as i64 - 1) / 2) as usize,
+        )
+    }
+
+    pub fn apply<T: Clone>(self, grid: &[Vec<T>]) -> Vec<Vec<T>> {
+        let dims = (grid.len(), grid.first().map_or(0, |row| row.len()));
+        let new_dims = self.apply_dims(dims);
+        let inverse = self.inverse();
+        (0..new_dims.0).map(|i| {
+            (0..new_dims.1).map(|j| {
+                let (src_i, src_j) = inverse.apply_coords((i, j), new_dims);
+                grid[src_i][src_j].clone()
+            }).collect()
+        }).collect()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Tile {
-    id: usize,
-    sides: [usize; 8],
-    content: [[bool; 10]; 10],
-    flipped_rows: bool,
-    flipped_cols: bool,
-    inverted: bool,
-    oriented: bool,
+    id: String,
+    content: Vec<Vec<bool>>,
+    orientation: D4,
 }
 
 impl std::fmt::Display for Tile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "id {}", self.id)?;
 
-        for i in 0..10 {
-            for j in 0..10 {
+        let (rows, cols) = self.dims();
+        for i in 0..rows {
+            for j in 0..cols {
                 let b = self.get_oriented(i, j);
                 write!(f, "{}", if b {'#'} else {'.'})?;
             }
-            writeln!(f, "")?;
+            writeln!(f)?;
         }
         Ok(())
     }
 }
 
-fn shift_reverse(val: usize) -> usize {
-    ((val as u16).reverse_bits() >> 6) as usize
-}
-
 impl Tile {
-    fn from_lines(id: usize, lines: Vec<&str>) -> Self {
-        let mut up = 0;
-        let mut down = 0;
-        let mut left = 0;
-        let mut right = 0;
-        let mut content = [[false; 10]; 10];
-        for (i, line) in lines.iter().enumerate() {
-            for (j, c) in line.bytes().enumerate() {
-                let b = if c == '#' as u8 {1} else {0};
-                if b == 1 {
-                    content[i][j] = true;
-                }
-                if i == 0 {
-                    up <<= 1;
-                    up += b;
-                } else if i == lines.len() - 1 {
-                    down <<= 1;
-                    down += b;
-                }
-                if j == 0 {
-                    left <<= 1;
-                    left += b;
-                } else if j == line.len() - 1 {
-                    right <<= 1;
-                    right += b;
-                }
-            }
+    fn from_lines(id: &str, lines: &[&str]) -> Option<Self> {
+        let content: Vec<Vec<bool>> = lines.iter()
+            .map(|line| line.bytes().map(|c| c == b'#').collect())
+            .collect();
+
+        // Tiles may be any size, but must at least be rectangular
+        if content.is_empty() || content[0].is_empty() || content.iter().any(|row| row.len() != content[0].len()) {
+            return None;
         }
 
-        Self {
-            id,
-            sides: [
-                left, shift_reverse(left),
-                up, shift_reverse(up),
-                right, shift_reverse(right),
-                down, shift_reverse(down),
-            ],
+        Some(Self {
+            id: id.to_string(),
             content,
-            flipped_cols: false,
-            flipped_rows: false,
-            inverted: false,
-            oriented: false,
-        }
+            orientation: D4::Identity,
+        })
     }
 
     pub fn from_input<'a, 'b>(mut lines: &'a[&'b str]) -> (Option<Self>, &'a[&'b str])
     {
         lazy_static!(
-            static ref TILE_RE: Regex = Regex::new(r"^Tile (\d{4}):$").unwrap();
+            static ref TILE_RE: Regex = Regex::new(r"^Tile (.+):$").unwrap();
         );
 
         let mut id = None;
         while !lines.is_empty() {
             if let Some(caps) = TILE_RE.captures(lines[0]) {
                 // Got a valid line
-                id = Some(caps[1].parse::<usize>().unwrap());
+                id = Some(caps[1].to_string());
                 break;
             }
             lines = &lines[1..];
@@ -96,9 +168,12 @@ impl Tile {
 
         (
             if let Some(id) = id {
-                let rows = lines[1..11].iter().map(|s| *s).collect();
-                lines = &lines[11..];
-                Some(Self::from_lines(id, rows))
+                lines = &lines[1..];
+                // Rows run until the next blank line
+                let len = lines.iter().position(|s| s.trim().is_empty()).unwrap_or(lines.len());
+                let rows = &lines[..len];
+                lines = &lines[len..];
+                Self::from_lines(&id, rows)
             } else {
                 None
             },
@@ -106,72 +181,73 @@ impl Tile {
         )
     }
 
-    fn orient_indices(&self, mut i: usize, mut j: usize) -> (usize, usize) {
-        if self.flipped_cols {
-            i = self.content.len() - i - 1;
-        }
-        if self.flipped_rows {
-            j = self.content[0].len() - j - 1;
-        }
-        if self.inverted {
-            std::mem::swap(&mut i, &mut j)
-        }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
 
-        (i, j)
+    pub fn orientation(&self) -> D4 {
+        self.orientation
+    }
+
+    // Dimensions of the tile as currently oriented, (rows, cols)
+    pub fn dims(&self) -> (usize, usize) {
+        self.orientation.apply_dims((self.content.len(), self.content[0].len()))
     }
 
     fn get_oriented(&self, i: usize, j: usize) -> bool {
-        let (i, j) = self.orient_indices(i, j);
+        let (i, j) = self.orientation.inverse().apply_coords((i, j), self.dims());
         self.content[i][j]
     }
 
-    fn is_oriented(&self, other: &Self, target_dir: usize) -> bool {
-        (0..10).all(|j| match target_dir {
-            0 => self.get_oriented(j, 0) == other.get_oriented(j, 9),
-            1 => self.get_oriented(0, j) == other.get_oriented(9, j),
-            2 => self.get_oriented(j, 9) == other.get_oriented(j, 0),
-            3 => self.get_oriented(9, j) == other.get_oriented(0, j),
-            _ => false,
-        })
+    fn set_orientation(&mut self, orientation: D4) {
+        self.orientation = orientation;
     }
 
-    pub fn orient_to(&mut self, other: &Self, dir: usize) -> bool {
-        assert!(other.oriented);
-        let target_idx = (dir+2) % 4;
-        if self.oriented {
-            return self.is_oriented(other, target_idx);
+    // Edge as currently oriented. Directions are 0 left, 1 up, 2 right, 3 down,
+    // always read top to bottom or left to right.
+    fn edge(&self, dir: usize) -> Vec<bool> {
+        let (rows, cols) = self.dims();
+        match dir {
+            0 => (0..rows).map(|i| self.get_oriented(i, 0)).collect(),
+            1 => (0..cols).map(|j| self.get_oriented(0, j)).collect(),
+            2 => (0..rows).map(|i| self.get_oriented(i, cols - 1)).collect(),
+            3 => (0..cols).map(|j| self.get_oriented(rows - 1, j)).collect(),
+            _ => vec![],
         }
+    }
 
-        for i in 0..8 {
-            self.flipped_cols = i & 0x01 != 0;
-            self.flipped_rows = i & 0x02 != 0;
-            self.inverted = i & 0x04 != 0;
-
-            if self.is_oriented(other, target_idx) {
-                self.oriented = true;
-                return true;
-            }
-        }
+    // All 4 edges of the unoriented tile, forwards and backwards
+    fn sides(&self) -> Vec<Vec<bool>> {
+        let mut plain = self.clone();
+        plain.set_orientation(D4::Identity);
+        (0..4).flat_map(|dir| {
+            let side = plain.edge(dir);
+            let mut rev = side.clone();
+            rev.reverse();
+            vec![side, rev]
+        }).collect()
+    }
 
-        false
+    fn is_oriented(&self, other: &Self, target_dir: usize) -> bool {
+        self.edge(target_dir) == other.edge((target_dir + 2) % 4)
     }
 }
 
-pub fn doit(tiles: &Vec<Tile>) -> HashMap<usize, HashSet<usize>> {
-    let mut assocs: Vec<Vec<usize>> = vec![vec![]; 1024];
+pub fn doit(tiles: &[Tile]) -> HashMap<usize, HashSet<usize>> {
+    let mut assocs: HashMap<Vec<bool>, Vec<usize>> = HashMap::new();
     for (idx, tile) in tiles.iter().enumerate() {
-        for i in 0..8 {
-            assocs[tile.sides[i]].push(idx);
+        for side in tile.sides() {
+            assocs.entry(side).or_default().push(idx);
         }
     }
 
     let mut mingle: HashMap<usize, HashSet<usize>> = HashMap::new();
-    for i in 0..1024 {
-        for l in assocs[i].iter() {
-            for r in assocs[i].iter() {
+    for assoc in assocs.values() {
+        for l in assoc.iter() {
+            for r in assoc.iter() {
                 if l != r {
-                    mingle.entry(*l).or_insert(HashSet::new()).insert(*r);
-                    mingle.entry(*r).or_insert(HashSet::new()).insert(*l);
+                    mingle.entry(*l).or_default().insert(*r);
+                    mingle.entry(*r).or_default().insert(*l);
                 }
             }
         }
@@ -180,67 +256,110 @@ pub fn doit(tiles: &Vec<Tile>) -> HashMap<usize, HashSet<usize>> {
     mingle
 }
 
-pub fn orient_tiles(tiles: &mut Vec<Tile>, mingle: &HashMap<usize, HashSet<usize>>) -> Vec<Vec<Tile>> {
-    // Find first corner
-    let mut corner = 0usize;
-    for (i, assocs) in mingle.iter() {
-        if assocs.len() <= 2 {
-            corner = *i;
-        }
-    }
-
-    let corner_assocs = mingle.get(&corner).unwrap().iter().map(|x| *x).collect::<Vec<usize>>();
-    tiles[corner].oriented = true;
-    let corner_tile = tiles[corner].clone();
-
-    // Found a corner. Make it "top left"
-    let mut neighbor_horiz = corner_assocs[0];
-    let mut neighbor_vert = corner_assocs[1];
-    let mut horiz_dir = (0..4).find(|i| tiles[neighbor_horiz].clone().orient_to(&corner_tile, *i)).unwrap();
-    let mut vert_dir = (0..4).find(|i| tiles[neighbor_vert].clone().orient_to(&corner_tile, *i)).unwrap();
-
-    if horiz_dir & 0x01 != 0 {
-        std::mem::swap(&mut horiz_dir, &mut vert_dir);
-        std::mem::swap(&mut neighbor_horiz, &mut neighbor_vert);
-    }
-
-//    println!("\n\n---------------\nHoriz {}, Vert {}", horiz_dir, vert_dir);
-
-    let dims = (tiles.len() as f32).sqrt() as usize;
-    assert_eq!(dims * dims, tiles.len());
-    let mut ordered_tiles = vec![];
-    let mut current = corner;
-    for r in 0..dims {
-        let mut row = vec![];
-        // Find below neighbor
-        let current_tile = tiles[current].clone();
-        let next = mingle[&current].iter().find(|n| tiles[**n].orient_to(&current_tile, vert_dir));
-        for c in 0..dims {
-            let current_tile = tiles[current].clone();
-            let next = mingle[&current].iter().find(|n| tiles[**n].orient_to(&current_tile, horiz_dir));
-            row.push(current_tile);
-            if let Some(thing) = next {
-                current = *thing;
-            } else if c != dims - 1 {
-                panic!("Next not found\n");
-            }
+// Backtracking state for laying tiles out row by row, left to right.
+struct Assembly<'a> {
+    tiles: &'a [Tile],
+    mingle: &'a HashMap<usize, HashSet<usize>>,
+    cols: usize,
+    size: usize,
+    placed: Vec<(usize, Tile)>,
+    used: Vec<bool>,
+}
+
+impl<'a> Assembly<'a> {
+    fn fits(&self, tile: &Tile) -> bool {
+        let pos = self.placed.len();
+        if !pos.is_multiple_of(self.cols) && !tile.is_oriented(&self.placed[pos - 1].1, 0) {
+            return false;
         }
-        if horiz_dir == 0 {
-            row.reverse();
+        if pos >= self.cols && !tile.is_oriented(&self.placed[pos - self.cols].1, 1) {
+            return false;
         }
-        ordered_tiles.push(row);
-        if let Some(thing) = next {
-            current = *thing;
-        } else if r != dims - 1 {
-            panic!("Next not found\n");
+        true
+    }
+
+    fn candidates(&self, order: &[usize]) -> Vec<usize> {
+        let pos = self.placed.len();
+        let anchor = if !pos.is_multiple_of(self.cols) {
+            Some(pos - 1)
+        } else if pos >= self.cols {
+            Some(pos - self.cols)
+        } else {
+            None
+        };
+
+        match anchor {
+            // Anything placed next to a tile has to share an edge with it
+            Some(anchor) => {
+                let mut next = self.mingle.get(&self.placed[anchor].0)
+                    .map(|s| s.iter().copied().filter(|i| !self.used[*i]).collect::<Vec<usize>>())
+                    .unwrap_or_default();
+                next.sort_unstable();
+                next
+            },
+            None => order.iter().copied().filter(|i| !self.used[*i]).collect(),
         }
     }
 
-    if vert_dir == 1 {
-        ordered_tiles.reverse();
+    fn search(&mut self, order: &[usize]) -> bool {
+        if self.placed.len() == self.size {
+            return true;
+        }
+
+        for idx in self.candidates(order) {
+            let mut tile = self.tiles[idx].clone();
+            for t in D4::all() {
+                tile.set_orientation(t);
+                if !self.fits(&tile) {
+                    continue;
+                }
+
+                self.used[idx] = true;
+                self.placed.push((idx, tile.clone()));
+                if self.search(order) {
+                    return true;
+                }
+                self.placed.pop();
+                self.used[idx] = false;
+            }
+        }
+
+        false
     }
+}
 
-    ordered_tiles
+// Lays the tiles out into a grid where every shared edge matches. Every grid
+// shape the tile count allows is tried, squarest first, and each is searched
+// exhaustively so ambiguous edge matches are backed out of rather than trusted.
+pub fn orient_tiles(tiles: &[Tile], mingle: &HashMap<usize, HashSet<usize>>) -> Option<Vec<Vec<Tile>>> {
+    let size = tiles.len();
+    let mut shapes: Vec<(usize, usize)> = (1..=size)
+        .filter(|r| size.is_multiple_of(*r))
+        .map(|r| (r, size / r))
+        .collect();
+    shapes.sort_by_key(|&(r, c)| r.abs_diff(c));
+
+    // Try to start from the tiles with the fewest neighbors, they're likely corners
+    let mut order: Vec<usize> = (0..size).collect();
+    order.sort_by_key(|i| mingle.get(i).map_or(0, |s| s.len()));
+
+    for (rows, cols) in shapes {
+        let mut assembly = Assembly {
+            tiles,
+            mingle,
+            cols,
+            size,
+            placed: vec![],
+            used: vec![false; size],
+        };
+
+        if assembly.search(&order) {
+            let mut placed = assembly.placed.into_iter().map(|(_, tile)| tile);
+            return Some((0..rows).map(|_| placed.by_ref().take(cols).collect()).collect());
+        }
+    }
+
+    None
 }
 
 pub struct Board {
@@ -253,20 +372,22 @@ impl std::fmt::Display for Board {
             for b in row.iter() {
                 write!(f, "{}", if *b {'#'} else {'.'})?;
             }
-            writeln!(f, "")?;
+            writeln!(f)?;
         }
         Ok(())
     }
 }
 
 impl Board {
-    pub fn from_oriented_tiles(tiles: &Vec<Vec<Tile>>) -> Self {
+    pub fn from_oriented_tiles(tiles: &[Vec<Tile>]) -> Self {
         let mut grid = vec![];
         for row in tiles.iter() {
-            for i in 1..9 {
+            let (rows, _) = row[0].dims();
+            for i in 1..rows - 1 {
                 let mut temp_row = vec![];
                 for tile in row.iter() {
-                    for j in 1..9 {
+                    let (_, cols) = tile.dims();
+                    for j in 1..cols - 1 {
                         temp_row.push(tile.get_oriented(i, j));
                     }
                 }
@@ -277,82 +398,347 @@ impl Board {
         Self { grid }
     }
 
+    pub fn find_pattern(&self, pattern: &Pattern) -> PatternSearch {
+        let width = self.grid.first().map_or(0, |row| row.len());
+        let mut matches = vec![];
+        let mut coverage = HashSet::new();
+        let mut seen_shapes = HashSet::new();
+        for t in D4::all() {
+            let mut shape: Vec<(usize, usize)> = pattern.cells.iter()
+                .map(|&idx| t.apply_coords(idx, (pattern.rows, pattern.cols)))
+                .collect();
+            shape.sort_unstable();
+            // Symmetric patterns look the same in several orientations, only count them once
+            if !seen_shapes.insert(shape.clone()) {
+                continue;
+            }
+
+            for i in 0..self.grid.len() {
+                for j in 0..width {
+                    if shape.iter().all(|(li, lj)| li + i < self.grid.len() && lj + j < width && self.grid[li + i][lj + j]) {
+                        coverage.extend(shape.iter().map(|(li, lj)| (li + i, lj + j)));
+                        matches.push(PatternMatch { orientation: t, position: (i, j) });
+                    }
+                }
+            }
+        }
+
+        let total = self.grid.iter().flatten().filter(|b| **b).count();
+        PatternSearch {
+            matches,
+            roughness: total - coverage.len(),
+            coverage,
+        }
+    }
+
     pub fn search_350(&self) -> HashSet<(usize, usize)> {
-        lazy_static!(
-            // Representation of
-            //                   #
-            // #    ##    ##    ###
-            //  #  #  #  #  #  #
-            static ref INDICES: [(usize, usize); 15] = [
-                (0, 18),
-                (1, 0), (1, 5), (1, 6), (1, 11), (1, 12), (1, 17), (1, 18), (1, 19),
-                (2, 1), (2, 4), (2, 7), (2, 10), (2, 13), (2, 16),
-            ];
-        );
+        self.find_pattern(&Pattern::sea_monster()).coverage
+    }
+}
 
-        let mut count = vec![vec![]; 8];
-        for i in 0..self.grid.len() {
-            for j in 0..self.grid.len() {
-                for k in 0..8 {
-                    if INDICES.iter().all(|(mut li, mut lj)| {
-                        // Dimensions are 3x20
-                        if k & 1 != 0 {
-                            li = 2 - li;
-                        }
-                        if k & 2 != 0 {
-                            lj = 19 - lj;
-                        }
-                        if k & 4 != 0 {
-                            std::mem::swap(&mut li, &mut lj);
-                        }
+// A shape to look for on a board, given as lines of '#' (must be set) and
+// ' ' (don't care).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    rows: usize,
+    cols: usize,
+    cells: Vec<(usize, usize)>,
+}
 
-                        if li + i >= self.grid.len() {
-                            return false;
-                        }
-                        if lj + j >= self.grid.len() {
-                            return false;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternMatch {
+    pub orientation: D4,
+    // Top left corner of the oriented pattern on the board
+    pub position: (usize, usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct PatternSearch {
+    pub matches: Vec<PatternMatch>,
+    // Every board cell covered by at least one match
+    pub coverage: HashSet<(usize, usize)>,
+    // Set cells on the board not covered by any match
+    pub roughness: usize,
+}
+
+impl Pattern {
+    pub fn parse(input: &str) -> Option<Self> {
+        let mut cells = vec![];
+        for (i, line) in input.lines().enumerate() {
+            for (j, c) in line.chars().enumerate() {
+                match c {
+                    '#' => cells.push((i, j)),
+                    ' ' => (),
+                    _ => { return None; }
+                }
+            }
+        }
+
+        // Trim down to the bounding box of the set cells
+        let min_i = cells.iter().map(|c| c.0).min()?;
+        let min_j = cells.iter().map(|c| c.1).min()?;
+        for cell in cells.iter_mut() {
+            cell.0 -= min_i;
+            cell.1 -= min_j;
+        }
+
+        Some(Self {
+            rows: cells.iter().map(|c| c.0).max()? + 1,
+            cols: cells.iter().map(|c| c.1).max()? + 1,
+            cells,
+        })
+    }
+
+    pub fn sea_monster() -> Self {
+        Self::parse(concat!(
+            "                  # \n",
+            "#    ##    ##    ###\n",
+            " #  #  #  #  #  #   \n",
+        )).unwrap()
+    }
+
+    pub fn dims(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    pub fn cells(&self) -> &[(usize, usize)] {
+        &self.cells
+    }
+}
+
+// Tile IDs laid out the way orient_tiles placed them, along with the
+// orientation each tile ended up in.
+pub fn layout_map(tiles: &[Vec<Tile>]) -> String {
+    let labels: Vec<Vec<String>> = tiles.iter()
+        .map(|row| row.iter().map(|tile| format!("{}:{:?}", tile.id, tile.orientation)).collect())
+        .collect();
+    let width = labels.iter().flatten().map(|label| label.len()).max().unwrap_or(0);
+
+    let mut out = String::new();
+    for row in labels.iter() {
+        let line = row.iter().map(|label| format!("{:<width$}", label, width = width)).collect::<Vec<String>>();
+        out.push_str(line.join(" ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pixel {
+    Off,
+    On,
+    BorderOff,
+    BorderOn,
+    Highlight,
+}
+
+// Colours for each Pixel, in declaration order
+const PALETTE: [[u8; 3]; 5] = [
+    [0xff, 0xff, 0xff], // Off
+    [0x00, 0x00, 0x00], // On
+    [0xdd, 0xdd, 0xee], // BorderOff
+    [0x66, 0x66, 0x88], // BorderOn
+    [0xe0, 0x20, 0x20], // Highlight
+];
+
+impl Pixel {
+    fn palette_index(self) -> u8 {
+        match self {
+            Pixel::Off => 0,
+            Pixel::On => 1,
+            Pixel::BorderOff => 2,
+            Pixel::BorderOn => 3,
+            Pixel::Highlight => 4,
+        }
+    }
+}
+
+// The assembled picture for exporting, optionally with the tile borders that
+// Board strips off left in place.
+#[derive(Debug, Clone)]
+pub struct Render {
+    cells: Vec<Vec<Pixel>>,
+    // Board row/col -> render row/col
+    row_map: Vec<usize>,
+    col_map: Vec<usize>,
+}
+
+impl Render {
+    pub fn new(tiles: &[Vec<Tile>], keep_borders: bool) -> Self {
+        let mut cells = vec![];
+        let mut row_map = vec![];
+        for row in tiles.iter() {
+            let (rows, _) = row[0].dims();
+            for i in 0..rows {
+                let border_row = i == 0 || i == rows - 1;
+                if border_row && !keep_borders {
+                    continue;
+                }
+                if !border_row {
+                    row_map.push(cells.len());
+                }
+
+                let mut temp_row = vec![];
+                for tile in row.iter() {
+                    let (_, cols) = tile.dims();
+                    for j in 0..cols {
+                        let border = border_row || j == 0 || j == cols - 1;
+                        if border && !keep_borders {
+                            continue;
                         }
-                        self.grid[li + i][lj + j]
-                    }) {
-                        count[k].push((i, j));
+                        temp_row.push(match (border, tile.get_oriented(i, j)) {
+                            (false, false) => Pixel::Off,
+                            (false, true) => Pixel::On,
+                            (true, false) => Pixel::BorderOff,
+                            (true, true) => Pixel::BorderOn,
+                        });
                     }
                 }
+                cells.push(temp_row);
             }
         }
 
-        let mut temp = HashSet::new();
-        for (k, cs) in count.iter().enumerate() {
-            for (i, j) in cs {
-                for (mut li, mut lj) in INDICES.iter() {
-                    if k & 1 != 0 {
-                        li = 2 - li;
-                    }
-                    if k & 2 != 0 {
-                        lj = 19 - lj;
-                    }
-                    if k & 4 != 0 {
-                        std::mem::swap(&mut li, &mut lj);
-                    }
-                    temp.insert((li + i, lj + j));
+        let mut col_map = vec![];
+        let mut pos = 0;
+        for tile in tiles.first().map_or(&[][..], |row| &row[..]) {
+            let (_, cols) = tile.dims();
+            for j in 0..cols {
+                let border = j == 0 || j == cols - 1;
+                if !border {
+                    col_map.push(pos);
+                }
+                if !border || keep_borders {
+                    pos += 1;
+                }
+            }
+        }
+
+        Self { cells, row_map, col_map }
+    }
+
+    // Highlight cells given in Board coordinates, e.g. PatternSearch::coverage
+    pub fn highlight(&mut self, cells: &HashSet<(usize, usize)>) {
+        for &(i, j) in cells.iter() {
+            if let (Some(&r), Some(&c)) = (self.row_map.get(i), self.col_map.get(j)) {
+                if self.cells[r][c] == Pixel::On {
+                    self.cells[r][c] = Pixel::Highlight;
                 }
             }
         }
+    }
+
+    pub fn dims(&self) -> (usize, usize) {
+        (self.cells.len(), self.cells.first().map_or(0, |row| row.len()))
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> Pixel {
+        self.cells[i][j]
+    }
+
+    // Plain PPM, one line of RGB triples per row, in the PNG's colours
+    pub fn to_ppm(&self) -> String {
+        let (rows, cols) = self.dims();
+        let mut out = format!("P3\n{} {}\n255\n", cols, rows);
+        for row in self.cells.iter() {
+            let line = row.iter().map(|p| {
+                let [r, g, b] = PALETTE[p.palette_index() as usize];
+                format!("{} {} {}", r, g, b)
+            }).collect::<Vec<String>>();
+            out.push_str(&line.join(" "));
+            out.push('\n');
+        }
+        out
+    }
 
-        temp
+    // Paletted PNG with each cell drawn as a scale x scale square.
+    pub fn to_png(&self, scale: usize) -> Vec<u8> {
+        let (rows, cols) = self.dims();
+        let (height, width) = (rows * scale, cols * scale);
+        let mut raw = Vec::with_capacity(height * (width + 1));
+        for row in self.cells.iter() {
+            let mut line = vec![0u8];
+            for p in row.iter() {
+                line.extend(std::iter::repeat_n(p.palette_index(), scale));
+            }
+            for _ in 0..scale {
+                raw.extend_from_slice(&line);
+            }
+        }
+
+        let mut ihdr = vec![];
+        ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+        // 8 bit depth, paletted, default compression/filter, no interlace
+        ihdr.extend_from_slice(&[8, 3, 0, 0, 0]);
+
+        let mut out = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+        png_chunk(&mut out, b"IHDR", &ihdr);
+        png_chunk(&mut out, b"PLTE", &PALETTE.concat());
+        png_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+        png_chunk(&mut out, b"IEND", &[]);
+        out
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for b in bytes.iter() {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for x in bytes.iter() {
+        a = (a + *x as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// zlib stream made of uncompressed deflate blocks. The pictures are small
+// enough that compressing them isn't worth the code.
+fn zlib_stored(bytes: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = bytes.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        out.push(if blocks.peek().is_none() { 1 } else { 0 });
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
     }
+    out.extend_from_slice(&adler32(bytes).to_be_bytes());
+    out
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn drive(filename: &str) {
+    fn read_tiles(filename: &str) -> Vec<Tile> {
         let input = std::fs::read_to_string(filename).unwrap();
-        let mut last_lines: & [&str] = &mut input.lines().collect::<Vec<&str>>();
+        let mut last_lines: &[&str] = &input.lines().collect::<Vec<&str>>();
 
         let mut tiles = vec![];
         while !last_lines.is_empty() {
-            let (tile, lines) = Tile::from_input(&mut last_lines);
+            let (tile, lines) = Tile::from_input(last_lines);
             if let Some(tile) = tile {
 //                println!("{}", tile);
                 tiles.push(tile);
@@ -360,16 +746,21 @@ mod tests {
             last_lines = lines;
         }
 
-        let mingle = doit(&tiles);
-        let mut acc = 1usize;
-        for (i, assocs) in mingle.iter() {
-            if assocs.len() <= 2 {
-//                println!("Corner tile {} ", tiles[*i]);
-                acc *= tiles[*i].id;
-            }
-        }
+        tiles
+    }
 
-        let tiles = orient_tiles(&mut tiles, &mingle);
+    fn drive(filename: &str) {
+        let tiles = read_tiles(filename);
+
+        let mingle = doit(&tiles);
+        let tiles = orient_tiles(&tiles, &mingle).unwrap();
+
+        // Corners of the assembled grid, ids needn't be numbers
+        let last = tiles.len() - 1;
+        let corners = [&tiles[0][0], &tiles[0][last], &tiles[last][0], &tiles[last][last]];
+        let ids: Vec<&str> = corners.iter().map(|tile| tile.id.as_str()).collect();
+        println!("Corner tiles: {}", ids.join(" "));
+        let acc = ids.iter().map(|id| id.parse::<usize>().ok()).product::<Option<usize>>();
 //        for row in tiles.iter() {
 //            for tile in row.iter() {
 //                println!("{}", tile);
@@ -378,10 +769,10 @@ mod tests {
 
         let board = Board::from_oriented_tiles(&tiles);
 //        println!("{}", board);
-        let used_indices = board.search_350();
+        let search = board.find_pattern(&Pattern::sea_monster());
 //        for i in 0..board.grid.len() {
 //            for j in 0..board.grid.len() {
-//                if used_indices.contains(&(i, j)) {
+//                if search.coverage.contains(&(i, j)) {
 //                    print!("O");
 //                } else if board.grid[i][j] {
 //                    print!("#");
@@ -391,38 +782,27 @@ mod tests {
 //            }
 //            println!("");
 //        }
-        println!("Part 1: {}", acc);
-        let acc = board.grid.iter().fold(0, |acc, row| acc + row.iter().fold(0, |ac, x| ac + if *x {1} else {0}));
-        println!("Part 2: {}", acc - used_indices.len());
+        if let Some(acc) = acc {
+            println!("Part 1: {}", acc);
+        }
+        println!("Part 2: {}", search.roughness);
     }
 
     #[test]
     fn can_i_doit() {
-        // Use however many vars to print an array flipped around.
-        let input = [
-            [1, 2, 3, 4,],
-            [5, 6, 7, 8,],
-            [9, 10, 11, 12,],
-            [13, 14, 15, 16,],
+        // Print an array flipped around every which way.
+        let input = vec![
+            vec![1, 2, 3, 4,],
+            vec![5, 6, 7, 8,],
+            vec![9, 10, 11, 12,],
+            vec![13, 14, 15, 16,],
         ];
 
-        for control in 0..8 {
-            println!("{:03b}", control);
-            for x in 0..input.len() {
-                for y in 0..input[0].len() {
-                    let mut i = x;
-                    let mut j = y;
-                    if control & 0x01 != 0 {
-                        i = input.len() - i - 1;
-                    }
-                    if control & 0x02 != 0 {
-                        j = input[0].len() - j - 1;
-                    }
-                    if control & 0x04 != 0 {
-                        // Flip i and j directions
-                        std::mem::swap(&mut i, &mut j);
-                    }
-                    print!("{:02} ", input[i][j]);
+        for t in D4::all() {
+            println!("{:?}", t);
+            for row in t.apply(&input) {
+                for val in row {
+                    print!("{:02} ", val);
                 }
                 println!();
             }
@@ -430,6 +810,184 @@ mod tests {
         }
     }
 
+    #[test]
+    fn d4_group() {
+        let grid = vec![
+            vec![1, 2, 3],
+            vec![4, 5, 6],
+        ];
+
+        assert_eq!(D4::Rot90.apply(&grid), vec![vec![4, 1], vec![5, 2], vec![6, 3]]);
+        assert_eq!(D4::FlipH.apply(&grid), vec![vec![3, 2, 1], vec![6, 5, 4]]);
+        assert_eq!(D4::AntiTranspose.apply(&grid), vec![vec![6, 3], vec![5, 2], vec![4, 1]]);
+
+        let images: HashSet<Vec<Vec<i32>>> = D4::all().map(|t| t.apply(&grid)).collect();
+        assert_eq!(images.len(), 8);
+
+        for a in D4::all() {
+            assert_eq!(a.compose(a.inverse()), D4::Identity);
+            assert_eq!(a.inverse().apply(&a.apply(&grid)), grid);
+            for b in D4::all() {
+                assert_eq!(a.compose(b).apply(&grid), a.apply(&b.apply(&grid)));
+                let (i, j) = b.apply_coords((1, 2), (2, 3));
+                assert_eq!(a.compose(b).apply_coords((1, 2), (2, 3)), a.apply_coords((i, j), b.apply_dims((2, 3))));
+            }
+        }
+    }
+
+    #[test]
+    fn find_pattern() {
+        let board = Board {
+            grid: [
+                "#..#..",
+                "##.##.",
+                "......",
+                "..#...",
+                ".##...",
+            ].iter().map(|line| line.bytes().map(|c| c == b'#').collect()).collect(),
+        };
+
+        // An L tromino, found in two different orientations
+        let pattern = Pattern::parse("#\n##").unwrap();
+        assert_eq!(pattern.dims(), (2, 2));
+        let search = board.find_pattern(&pattern);
+        assert_eq!(search.matches.len(), 3);
+        assert!(search.matches.contains(&PatternMatch { orientation: D4::Identity, position: (0, 0) }));
+        assert!(search.matches.contains(&PatternMatch { orientation: D4::Identity, position: (0, 3) }));
+        assert!(search.matches.contains(&PatternMatch { orientation: D4::Rot270, position: (3, 1) }));
+        assert_eq!(search.coverage.len(), 9);
+        assert_eq!(search.roughness, 0);
+
+        // Symmetric patterns don't get reported once per orientation
+        let search = board.find_pattern(&Pattern::parse("  \n ##").unwrap());
+        assert_eq!(search.matches.len(), 6);
+        assert_eq!(search.roughness, 0);
+
+        assert!(Pattern::parse("#.#").is_none());
+        assert!(Pattern::parse("   ").is_none());
+        assert_eq!(Pattern::sea_monster().cells().len(), 15);
+    }
+
+    #[test]
+    fn export() {
+        let tiles = read_tiles("res/20/input_simple.txt");
+        let oriented = orient_tiles(&tiles, &doit(&tiles)).unwrap();
+        let board = Board::from_oriented_tiles(&oriented);
+        let search = board.find_pattern(&Pattern::sea_monster());
+
+        let map = layout_map(&oriented);
+        assert_eq!(map.lines().count(), 3);
+        for tile in tiles.iter() {
+            assert!(map.contains(tile.id()));
+        }
+
+        let mut render = Render::new(&oriented, false);
+        assert_eq!(render.dims(), (24, 24));
+        render.highlight(&search.coverage);
+        for i in 0..24 {
+            for j in 0..24 {
+                let expected = match (search.coverage.contains(&(i, j)), board.grid[i][j]) {
+                    (true, _) => Pixel::Highlight,
+                    (false, true) => Pixel::On,
+                    (false, false) => Pixel::Off,
+                };
+                assert_eq!(render.get(i, j), expected);
+            }
+        }
+        let ppm = render.to_ppm();
+        assert!(ppm.starts_with("P3\n24 24\n255\n"));
+        let rgb = |i: usize, j: usize| -> Vec<String> {
+            let row: Vec<&str> = ppm.lines().nth(3 + i).unwrap().split(' ').collect();
+            row[3 * j..3 * j + 3].iter().map(|s| s.to_string()).collect()
+        };
+        let highlighted = *search.coverage.iter().next().unwrap();
+        let plain = (0..24).flat_map(|i| (0..24).map(move |j| (i, j))).find(|&(i, j)| render.get(i, j) == Pixel::On).unwrap();
+        assert_ne!(rgb(highlighted.0, highlighted.1), rgb(plain.0, plain.1));
+        assert_eq!(rgb(plain.0, plain.1), vec!["0", "0", "0"]);
+
+        // Same picture with borders, highlights still land on the same cells
+        let mut bordered = Render::new(&oriented, true);
+        assert_eq!(bordered.dims(), (30, 30));
+        bordered.highlight(&search.coverage);
+        let count = |r: &Render, p: Pixel| {
+            let (rows, cols) = r.dims();
+            (0..rows).flat_map(|i| (0..cols).map(move |j| (i, j))).filter(|&(i, j)| r.get(i, j) == p).count()
+        };
+        assert_eq!(count(&bordered, Pixel::Highlight), search.coverage.len());
+        assert_eq!(count(&bordered, Pixel::On), count(&render, Pixel::On));
+
+        let png = bordered.to_png(4);
+        assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+        assert_eq!(&png[16..24], &[0, 0, 0, 120, 0, 0, 0, 120]);
+        assert_eq!(&png[png.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn rectangular_tiles() {
+        // Cut a 3x4 grid of 5x7 tiles out of a pseudo random picture, where
+        // neighbors share their border row or column.
+        let (rows, cols, height, width) = (3, 4, 5, 7);
+        let mut seed = 12345usize;
+        let picture: Vec<Vec<bool>> = (0..rows * (height - 1) + 1).map(|_| {
+            (0..cols * (width - 1) + 1).map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                seed >> 62 == 0
+            }).collect()
+        }).collect();
+
+        let mut input = String::new();
+        for r in 0..rows {
+            for c in 0..cols {
+                input.push_str(&format!("Tile T-{}{}:\n", r, c));
+                let at = |i: usize, j: usize| picture[r * (height - 1) + i][c * (width - 1) + j];
+                if (r + c) % 2 == 0 {
+                    for i in 0..height {
+                        input.extend((0..width).map(|j| if at(i, j) {'#'} else {'.'}));
+                        input.push('\n');
+                    }
+                } else {
+                    // Every other tile is given rotated a quarter turn
+                    for j in 0..width {
+                        input.extend((0..height).rev().map(|i| if at(i, j) {'#'} else {'.'}));
+                        input.push('\n');
+                    }
+                }
+                input.push('\n');
+            }
+        }
+
+        let mut last_lines: &[&str] = &input.lines().collect::<Vec<&str>>();
+        let mut tiles = vec![];
+        while !last_lines.is_empty() {
+            let (tile, lines) = Tile::from_input(last_lines);
+            if let Some(tile) = tile {
+                tiles.push(tile);
+            }
+            last_lines = lines;
+        }
+        assert_eq!(tiles.len(), rows * cols);
+        assert_eq!(tiles[5].id(), "T-11");
+
+        let mingle = doit(&tiles);
+        let oriented = orient_tiles(&tiles, &mingle).unwrap();
+        let board = Board::from_oriented_tiles(&oriented);
+
+        let expected = (0..picture.len())
+            .filter(|i| i % (height - 1) != 0)
+            .flat_map(|i| (0..picture[0].len()).filter(|j| j % (width - 1) != 0).map(move |j| (i, j)))
+            .filter(|&(i, j)| picture[i][j])
+            .count();
+        let actual = board.grid.iter().flatten().filter(|b| **b).count();
+        assert_eq!(actual, expected);
+        assert_eq!(board.grid.len() * board.grid[0].len(), rows * (height - 2) * cols * (width - 2));
+    }
+
     #[test]
     fn it_works() {
         drive("res/20/input_simple.txt");
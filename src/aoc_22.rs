@@ -1,187 +1,557 @@
-use std::collections::{HashSet,HashMap,VecDeque,hash_map::DefaultHasher};
-use std::hash::{Hash, Hasher};
-use std::sync::Mutex;
-use lazy_static::lazy_static;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap,HashSet,HashMap,VecDeque};
 
+pub type Decks = Vec<VecDeque<usize>>;
+
+// Deck sizes for a sub-game, given the cards just drawn and how many cards
+// each of those players has left. None settles the round on the highest card.
+pub type RecurseFn = fn(&[usize], &[usize]) -> Option<Vec<usize>>;
+pub type ScoreFn = fn(&VecDeque<usize>) -> usize;
+
+pub fn never_recurse(_drawn: &[usize], _left: &[usize]) -> Option<Vec<usize>> {
+    None
+}
+
+// The puzzle's rule: recurse when everyone has at least as many cards left
+// as the value they drew, and take that many into the sub-game
+pub fn recurse_by_card(drawn: &[usize], left: &[usize]) -> Option<Vec<usize>> {
+    if drawn.iter().zip(left.iter()).all(|(d, l)| d <= l) {
+        Some(drawn.to_vec())
+    } else {
+        None
+    }
+}
+
+// Bottom card times 1, the one above it times 2, and so on
+pub fn standard_score(deck: &VecDeque<usize>) -> usize {
+    deck.iter().rev().enumerate().map(|(i, val)| (i + 1) * val).sum()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rules {
+    pub recurse: RecurseFn,
+    // Wins a game that gets back to an earlier position, or the first player
+    // still in if they're out
+    pub loop_winner: usize,
+    pub score: ScoreFn,
+}
+
+impl Rules {
+    pub fn combat() -> Self {
+        Self { recurse: never_recurse, loop_winner: 0, score: standard_score }
+    }
+
+    pub fn recursive_combat() -> Self {
+        Self { recurse: recurse_by_card, ..Self::combat() }
+    }
+
+    pub fn with_recurse(mut self, recurse: RecurseFn) -> Self {
+        self.recurse = recurse;
+        self
+    }
+
+    pub fn with_loop_winner(mut self, player: usize) -> Self {
+        self.loop_winner = player;
+        self
+    }
+
+    pub fn with_score(mut self, score: ScoreFn) -> Self {
+        self.score = score;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    GameStart { game: usize, depth: usize },
+    // Decks as they were before the cards in drawn, (player, card), came off
+    Round { game: usize, round: usize, decks: Decks, drawn: Vec<(usize, usize)> },
+    SubGame { game: usize, round: usize },
+    RoundWon { game: usize, round: usize, winner: usize },
+    // A position came round again, which ends the game
+    Loop { game: usize, round: usize, winner: usize },
+    GameEnd { game: usize, winner: Option<usize>, decks: Decks },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    // Rounds in each game, by game number less one. Sub-games answered from
+    // the memo aren't played again and aren't counted.
+    pub rounds: Vec<usize>,
+    pub max_depth: usize,
+    // Games ended by the loop rule
+    pub loops: usize,
+    pub memo_hits: usize,
+}
+
+impl Stats {
+    pub fn games(&self) -> usize {
+        self.rounds.len()
+    }
+
+    pub fn sub_games(&self) -> usize {
+        self.rounds.len().saturating_sub(1)
+    }
+
+    pub fn total_rounds(&self) -> usize {
+        self.rounds.iter().sum()
+    }
+}
+
+// State the whole tree of games shares, handed down to each sub-game in turn
+#[derive(Debug, Clone, Default)]
+struct Shared {
+    // Winner of every sub-game played, by starting decks
+    memo: HashMap<Decks, Option<usize>>,
+    log: Option<Vec<Event>>,
+    stats: Stats,
+}
+
+// Any number of players. Each round everyone still holding cards draws one,
+// and the round's winner puts their own card at the bottom of their deck
+// followed by the others, highest first.
 #[derive(Debug, Clone)]
 pub struct Game {
-    left: VecDeque<usize>,
-    right: VecDeque<usize>,
-    history: HashSet<(usize, usize)>,
+    decks: Decks,
+    rules: Rules,
+    // Every position seen so far, compared exactly
+    history: HashSet<Decks>,
+    shared: Shared,
+    // Game number, counting every game in the tree in the order played
+    id: usize,
+    depth: usize,
+    round: usize,
+    // Set when the loop rule ends the game
+    looped: Option<usize>,
+    ended: bool,
 }
 
 impl Game {
     pub fn new(left: &VecDeque<usize>, right: &VecDeque<usize>) -> Self {
-        Self {
-            left: left.clone(), right: right.clone(), history: HashSet::new(),
-        }
+        Self::with_rules(&[left.clone(), right.clone()], Rules::recursive_combat())
     }
 
-    pub fn has_winner(&self) -> bool {
-        self.left.is_empty() || self.right.is_empty()
+    pub fn with_rules(decks: &[VecDeque<usize>], rules: Rules) -> Self {
+        Self::sub_game(decks, rules, Shared::default(), 0)
     }
 
-    pub fn get_winner(&self) -> Option<Vec<usize>> {
-        if self.has_winner() {
-            if self.left.is_empty() {
-                Some(self.right.iter().map(|x| *x).collect())
-            } else {
-                Some(self.left.iter().map(|x| *x).collect())
-            }
-        } else {
-            None
+    fn sub_game(decks: &[VecDeque<usize>], rules: Rules, mut shared: Shared, depth: usize) -> Self {
+        shared.stats.rounds.push(0);
+        shared.stats.max_depth = shared.stats.max_depth.max(depth);
+        let id = shared.stats.rounds.len();
+        if let Some(log) = shared.log.as_mut() {
+            log.push(Event::GameStart { game: id, depth });
+        }
+        Self {
+            decks: decks.to_vec(), rules, history: HashSet::new(), shared, id, depth, round: 0, looped: None, ended: false,
         }
     }
 
-    pub fn turn(&mut self) {
-        if self.has_winner() {
-            return;
+    // Records events from here on. Sub-games are then always played out in
+    // full rather than taken from the memo, so the log has all of them.
+    pub fn enable_log(&mut self) {
+        if self.shared.log.is_none() {
+            self.shared.log = Some(vec![Event::GameStart { game: self.id, depth: self.depth }]);
         }
+    }
+
+    pub fn log(&self) -> Option<&[Event]> {
+        self.shared.log.as_deref()
+    }
+
+    // Counts for this game and every sub-game it has played
+    pub fn stats(&self) -> &Stats {
+        &self.shared.stats
+    }
 
-        if self.left[0] > self.right[0] {
-            self.win_p1();
-        } else {
-            self.win_p2();
+    fn record(&mut self, event: Event) {
+        if let Some(log) = self.shared.log.as_mut() {
+            log.push(event);
         }
     }
 
-    fn win_p1(&mut self) {
-        let left_front = self.left.pop_front().unwrap();
-        let right_front = self.right.pop_front().unwrap();
-        self.left.push_back(left_front);
-        self.left.push_back(right_front);
+    pub fn decks(&self) -> &[VecDeque<usize>] {
+        &self.decks
     }
 
-    fn win_p2(&mut self) {
-        let left_front = self.left.pop_front().unwrap();
-        let right_front = self.right.pop_front().unwrap();
-        self.right.push_back(right_front);
-        self.right.push_back(left_front);
+    fn active(&self) -> Vec<usize> {
+        (0..self.decks.len()).filter(|p| !self.decks[*p].is_empty()).collect()
     }
 
-    fn game_hash(&self) -> (usize, usize) {
-        let mut hasher = DefaultHasher::new();
-        self.left.hash(&mut hasher);
-        let hash_1 = hasher.finish() as usize;
-        let mut hasher = DefaultHasher::new();
-        self.right.hash(&mut hasher);
-        let hash_2 = hasher.finish() as usize;
+    // Whether the loop rule ended this game
+    pub fn looped(&self) -> bool {
+        self.looped.is_some()
+    }
 
-        (hash_1, hash_2)
+    pub fn has_winner(&self) -> bool {
+        self.looped.is_some() || self.active().len() <= 1
     }
 
-    fn check_history(&mut self) -> bool {
-        // Check history
-        let hash = self.game_hash();
-        if self.history.contains(&hash) {
-            return true;
+    // None until the game is over, and if everyone ran out of cards at once
+    pub fn winner(&self) -> Option<usize> {
+        if self.looped.is_some() {
+            return self.looped;
         }
+        match self.active()[..] {
+            [player] => Some(player),
+            _ => None,
+        }
+    }
 
-        self.history.insert(hash);
+    pub fn get_winner(&self) -> Option<Vec<usize>> {
+        self.winner().map(|player| self.decks[player].iter().copied().collect())
+    }
 
-        false
+    pub fn score(&self) -> Option<usize> {
+        self.winner().map(|player| (self.rules.score)(&self.decks[player]))
     }
 
-    fn run_sub_game(&mut self) -> bool {
-        lazy_static! {
-            static ref HISTORY: Mutex<HashMap<(usize, usize), bool>> = Mutex::new(HashMap::new());
-        };
-        let hash = self.game_hash();
-        if let Some(val) = HISTORY.lock().unwrap().get(&hash) {
-            return *val;
+    fn draw(&mut self) -> Vec<(usize, usize)> {
+        self.round += 1;
+        self.shared.stats.rounds[self.id - 1] += 1;
+        let decks = self.shared.log.as_ref().map(|_| self.decks.clone());
+        let drawn: Vec<(usize, usize)> = self.active().into_iter().map(|p| (p, self.decks[p].pop_front().unwrap())).collect();
+        if let Some(decks) = decks {
+            self.record(Event::Round { game: self.id, round: self.round, decks, drawn: drawn.clone() });
         }
+        drawn
+    }
 
-        // Build new left hand
-        let mut sub_left = VecDeque::new();
-        for val in self.left.iter().skip(1).take(self.left[0]) {
-            sub_left.push_back(*val);
+    fn note_end(&mut self) {
+        if self.ended || !self.has_winner() {
+            return;
         }
+        self.ended = true;
+        self.record(Event::GameEnd { game: self.id, winner: self.winner(), decks: self.decks.clone() });
+    }
 
-        // Build new right hand
-        let mut sub_right = VecDeque::new();
-        for val in self.right.iter().skip(1).take(self.right[0]) {
-            sub_right.push_back(*val);
-        }
+    // Highest card, the earlier player on a tie
+    fn highest(drawn: &[(usize, usize)]) -> usize {
+        drawn.iter().max_by_key(|(p, card)| (*card, Reverse(*p))).unwrap().0
+    }
 
-        // Run the game to done
-        let mut sub_game = Self::new( &sub_left, &sub_right );
-        while !sub_game.has_winner() {
-            sub_game.turn_2();
+    fn award(&mut self, winner: usize, drawn: &[(usize, usize)]) {
+        let own = drawn.iter().find(|(p, _)| *p == winner).unwrap().1;
+        let mut rest: Vec<usize> = drawn.iter().filter(|(p, _)| *p != winner).map(|(_, card)| *card).collect();
+        rest.sort_unstable_by(|a, b| b.cmp(a));
+        self.decks[winner].push_back(own);
+        self.decks[winner].extend(rest);
+        self.record(Event::RoundWon { game: self.id, round: self.round, winner });
+        self.note_end();
+    }
+
+    // A round of plain Combat, with no loop rule
+    pub fn turn(&mut self) {
+        if self.has_winner() {
+            return;
         }
 
-        HISTORY.lock().unwrap().insert((hash.1, hash.0), sub_game.left.is_empty());
-        HISTORY.lock().unwrap().insert(hash, !sub_game.left.is_empty());
+        let drawn = self.draw();
+        self.award(Self::highest(&drawn), &drawn);
+    }
+
+    fn run_sub_game(&mut self, drawn: &[(usize, usize)], sizes: &[usize]) -> Option<usize> {
+        let decks: Decks = drawn.iter()
+            .zip(sizes.iter())
+            .map(|((p, _), size)| self.decks[*p].iter().take(*size).copied().collect())
+            .collect();
 
-        !sub_game.left.is_empty()
+        let cached = match self.shared.log {
+            Some(_) => None,
+            None => self.shared.memo.get(&decks).copied(),
+        };
+        let winner = match cached {
+            Some(winner) => {
+                self.shared.stats.memo_hits += 1;
+                winner
+            },
+            None => {
+                self.record(Event::SubGame { game: self.id, round: self.round });
+                let shared = std::mem::take(&mut self.shared);
+                let mut sub_game = Self::sub_game(&decks, self.rules, shared, self.depth + 1);
+                let winner = sub_game.play();
+                self.shared = sub_game.shared;
+                self.shared.memo.insert(decks, winner);
+                winner
+            },
+        };
+
+        // Sub-game players are the ones who drew, in the same order
+        winner.map(|w| drawn[w].0)
     }
 
+    // A round under the game's rules
     pub fn turn_2(&mut self) {
         if self.has_winner() {
             return;
         }
 
         // Check history
-        if self.check_history() {
-            // Player 1 wins
-            while !self.has_winner() {
-                self.win_p1();
-            }
+        if !self.history.insert(self.decks.clone()) {
+            let active = self.active();
+            let winner = if active.contains(&self.rules.loop_winner) { self.rules.loop_winner } else { active[0] };
+            self.looped = Some(winner);
+            self.shared.stats.loops += 1;
+            self.record(Event::Loop { game: self.id, round: self.round, winner });
+            self.note_end();
             return;
         }
 
-        if self.left[0] > self.left.len() - 1 || self.right[0] > self.right.len() - 1 {
-            // Cannot recurse, do normal check
-            self.turn();
-        } else {
-            // Run a sub game and propogate winner
-            if self.run_sub_game() {
-                self.win_p1();
-            } else {
-                self.win_p2();
+        let drawn = self.draw();
+        let cards: Vec<usize> = drawn.iter().map(|(_, card)| *card).collect();
+        let left: Vec<usize> = drawn.iter().map(|(p, _)| self.decks[*p].len()).collect();
+        let winner = (self.rules.recurse)(&cards, &left)
+            .and_then(|sizes| self.run_sub_game(&drawn, &sizes))
+            .unwrap_or_else(|| Self::highest(&drawn));
+        self.award(winner, &drawn);
+    }
+
+    pub fn play(&mut self) -> Option<usize> {
+        while !self.has_winner() {
+            self.turn_2();
+        }
+        self.note_end();
+        self.winner()
+    }
+}
+
+fn join_deck(deck: &VecDeque<usize>) -> String {
+    deck.iter().map(|card| card.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+// The log written out the way the puzzle text walks through its example
+pub fn replay(log: &[Event]) -> String {
+    let mut out = String::new();
+    let mut games = vec![];
+    for event in log.iter() {
+        match event {
+            Event::GameStart { game, .. } => {
+                games.push(*game);
+                out += &format!("=== Game {} ===\n", game);
+            },
+            Event::Round { game, round, decks, drawn } => {
+                out += &format!("\n-- Round {} (Game {}) --\n", round, game);
+                for (p, deck) in decks.iter().enumerate() {
+                    out += &format!("Player {}'s deck: {}\n", p + 1, join_deck(deck));
+                }
+                for (p, card) in drawn.iter() {
+                    out += &format!("Player {} plays: {}\n", p + 1, card);
+                }
+            },
+            Event::SubGame { .. } => out += "Playing a sub-game to determine the winner...\n\n",
+            Event::RoundWon { game, round, winner } => {
+                out += &format!("Player {} wins round {} of game {}!\n", winner + 1, round, game);
+            },
+            Event::Loop { .. } => (),
+            Event::GameEnd { game, winner, decks } => {
+                match winner {
+                    Some(winner) => out += &format!("The winner of game {} is player {}!\n", game, winner + 1),
+                    None => out += &format!("Game {} has no winner!\n", game),
+                }
+                games.pop();
+                match games.last() {
+                    Some(parent) => out += &format!("\n...anyway, back to game {}.\n", parent),
+                    None => {
+                        out += "\n\n== Post-game results ==\n";
+                        for (p, deck) in decks.iter().enumerate() {
+                            out += &format!("Player {}'s deck: {}\n", p + 1, join_deck(deck));
+                        }
+                    },
+                }
+            },
+        }
+    }
+    out
+}
+
+// SplitMix64. Small, seedable, and plenty for shuffling cards.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in 0..bound, throwing away the uneven top end. None if the
+    // range is empty.
+    pub fn below(&mut self, bound: u64) -> Option<u64> {
+        let zone = u64::MAX - u64::MAX.checked_rem(bound)?;
+        loop {
+            let val = self.next_u64();
+            if val < zone {
+                return Some(val % bound);
             }
         }
     }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i as u64 + 1).unwrap() as usize;
+            items.swap(i, j);
+        }
+    }
 }
 
-pub fn doit(input: &String) {
-    let mut lines = input.lines();
+// Shuffles cards 1..=cards and deals them round the table one at a time.
+// None if there's nobody to deal to.
+pub fn deal(rng: &mut Rng, cards: usize, players: usize) -> Option<Decks> {
+    if players == 0 {
+        return None;
+    }
+    let mut pack: Vec<usize> = (1..=cards).collect();
+    rng.shuffle(&mut pack);
+    let mut decks = vec![VecDeque::new(); players];
+    for (i, card) in pack.into_iter().enumerate() {
+        decks[i % players].push_back(card);
+    }
+    Some(decks)
+}
 
-    let player_1 = lines.next().unwrap();
-    assert_eq!(player_1, String::from("Player 1:"));
+#[derive(Debug, Clone, Copy)]
+pub struct Study {
+    pub seed: u64,
+    pub games: usize,
+    pub cards: usize,
+    pub players: usize,
+    pub rules: Rules,
+    pub threads: usize,
+}
 
-    let mut nums_1 = VecDeque::new();
-    let mut nums_2 = VecDeque::new();
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub games: usize,
+    // Games won by each player
+    pub wins: Vec<usize>,
+    pub no_winner: usize,
+    // Rounds in the top level game -> number of games that long
+    pub lengths: BTreeMap<usize, usize>,
+    // Games the loop rule ended at the top level
+    pub looped: usize,
+    // Times the loop rule fired anywhere, sub-games included
+    pub loops: usize,
+    pub sub_games: usize,
+}
 
-    let mut current_player = &mut nums_1;
-    while let Some(line) = lines.next() {
-        if line.is_empty() {
-            continue;
+impl Report {
+    fn add_game(&mut self, game: &Game) {
+        self.games += 1;
+        match game.winner() {
+            Some(player) => self.wins[player] += 1,
+            None => self.no_winner += 1,
         }
-        if line == "Player 2:" {
-            current_player = &mut nums_2;
-            continue;
+        *self.lengths.entry(game.stats().rounds[0]).or_default() += 1;
+        if game.looped() {
+            self.looped += 1;
         }
+        self.loops += game.stats().loops;
+        self.sub_games += game.stats().sub_games();
+    }
 
-        current_player.push_back(line.parse::<usize>().unwrap());
+    fn merge(&mut self, other: &Self) {
+        self.games += other.games;
+        for (mine, theirs) in self.wins.iter_mut().zip(other.wins.iter()) {
+            *mine += theirs;
+        }
+        self.no_winner += other.no_winner;
+        for (len, count) in other.lengths.iter() {
+            *self.lengths.entry(*len).or_default() += count;
+        }
+        self.looped += other.looped;
+        self.loops += other.loops;
+        self.sub_games += other.sub_games;
     }
 
-    println!("{:?} {:?}", nums_1, nums_2);
+    pub fn mean_length(&self) -> f64 {
+        let total: usize = self.lengths.iter().map(|(len, count)| len * count).sum();
+        total as f64 / self.games.max(1) as f64
+    }
+}
 
-    let mut game = Game::new(&nums_1, &nums_2);
-    while !game.has_winner() {
-        game.turn();
+// Plays study.games random deals spread over study.threads threads. Every
+// game gets its own generator seeded from the study seed and its index, so
+// the report doesn't depend on the thread count. None without any players.
+pub fn study(study: &Study) -> Option<Report> {
+    if study.players == 0 {
+        return None;
     }
-    let mut winning_hand = game.get_winner().unwrap();
-    winning_hand.reverse();
-    println!("Part 1: {}", winning_hand.iter().enumerate().fold(0, |acc, (i, val)| acc + (i+1) * val));
+    let threads = study.threads.max(1);
+    let empty = Report { wins: vec![0; study.players], ..Report::default() };
+    let reports: Vec<Report> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let mut report = empty.clone();
+                scope.spawn(move || {
+                    for i in (t..study.games).step_by(threads) {
+                        let mut rng = Rng::new(Rng::new(study.seed ^ i as u64).next_u64());
+                        let decks = deal(&mut rng, study.cards, study.players).unwrap();
+                        let mut game = Game::with_rules(&decks, study.rules);
+                        game.play();
+                        report.add_game(&game);
+                    }
+                    report
+                })
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+
+    Some(reports.iter().fold(empty, |mut acc, report| {
+        acc.merge(report);
+        acc
+    }))
+}
+
+// Where parse_decks gave up, by line number from 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeckError {
+    BadCard { line: usize },
+    // A card before any "Player N:" header
+    NoPlayer { line: usize },
+}
 
-    let mut game = Game::new(&nums_1, &nums_2);
-    while !game.has_winner() {
-        game.turn_2();
+// Decks under "Player N:" headers, for any number of players
+pub fn parse_decks(input: &str) -> Result<Decks, DeckError> {
+    let mut decks = vec![];
+    for (i, line) in input.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with("Player ") {
+            decks.push(VecDeque::new());
+            continue;
+        }
+
+        let card = line.parse::<usize>().map_err(|_| DeckError::BadCard { line: i })?;
+        decks.last_mut().ok_or(DeckError::NoPlayer { line: i })?.push_back(card);
     }
-    let mut winning_hand = game.get_winner().unwrap();
-    winning_hand.reverse();
-    println!("Part 2: {}", winning_hand.iter().enumerate().fold(0, |acc, (i, val)| acc + (i+1) * val));
+    Ok(decks)
+}
+
+pub fn doit(input: &String) {
+    let decks = parse_decks(input).unwrap();
+    println!("{:?}", decks);
+
+    // Plain Combat can go round forever too, so it gets the loop rule
+    let mut game = Game::with_rules(&decks, Rules::combat());
+    game.play();
+    println!("Part 1: {}", game.score().unwrap());
+
+    let mut game = Game::with_rules(&decks, Rules::recursive_combat());
+    game.play();
+    println!("Part 2: {}", game.score().unwrap());
+    let stats = game.stats();
+    println!("{} games, {} rounds, depth {}, {} loops, {} memo hits", stats.games(), stats.total_rounds(), stats.max_depth, stats.loops, stats.memo_hits);
 }
 
 #[cfg(test)]
@@ -198,6 +568,116 @@ mod tests {
         drive("res/22/input_simple.txt");
     }
 
+    #[test]
+    fn engine() {
+        let decks = parse_decks(&std::fs::read_to_string("res/22/input_simple.txt").unwrap()).unwrap();
+        let mut game = Game::new(&decks[0], &decks[1]);
+        assert_eq!(game.play(), Some(1));
+        assert_eq!(game.score(), Some(291));
+
+        let mut game = Game::with_rules(&decks, Rules::recursive_combat().with_recurse(never_recurse));
+        assert_eq!(game.play(), Some(1));
+        assert_eq!(game.score(), Some(306));
+
+        let mut game = Game::with_rules(&decks, Rules::recursive_combat().with_score(|deck| deck.len()));
+        game.play();
+        assert_eq!(game.score(), Some(10));
+
+        // The loop rule, and handing the win to someone else instead
+        let decks = parse_decks(&std::fs::read_to_string("res/22/input_inf.txt").unwrap()).unwrap();
+        assert_eq!(Game::with_rules(&decks, Rules::recursive_combat()).play(), Some(0));
+        assert_eq!(Game::with_rules(&decks, Rules::combat().with_loop_winner(1)).play(), Some(1));
+
+        // Three players, B runs out on the second round
+        let decks: Decks = vec![vec![5, 1].into(), vec![3, 4].into(), vec![2, 6].into()];
+        let mut game = Game::with_rules(&decks, Rules::combat());
+        assert_eq!(game.play(), Some(2));
+        assert_eq!(game.get_winner(), Some(vec![4, 3, 6, 2, 5, 1]));
+        assert_eq!(game.score(), Some(80));
+        let mut game = Game::with_rules(&decks, Rules::recursive_combat());
+        assert!(game.play().is_some());
+        assert_eq!(game.decks().iter().map(|d| d.len()).sum::<usize>(), 6);
+    }
+
+    #[test]
+    fn transcript() {
+        let decks = parse_decks(&std::fs::read_to_string("res/22/input_simple.txt").unwrap()).unwrap();
+        let mut game = Game::with_rules(&decks, Rules::recursive_combat());
+        game.enable_log();
+        game.play();
+        let text = replay(game.log().unwrap());
+        println!("{}", text);
+
+        assert!(text.starts_with("=== Game 1 ===\n\n-- Round 1 (Game 1) --\nPlayer 1's deck: 9, 2, 6, 3, 1\nPlayer 2's deck: 5, 8, 4, 7, 10\nPlayer 1 plays: 9\nPlayer 2 plays: 5\nPlayer 1 wins round 1 of game 1!\n"));
+        assert!(text.contains("Player 2 plays: 3\nPlaying a sub-game to determine the winner...\n\n=== Game 2 ===\n\n-- Round 1 (Game 2) --\nPlayer 1's deck: 9, 8, 5, 2\nPlayer 2's deck: 10, 1, 7\n"));
+        assert!(text.contains("The winner of game 2 is player 2!\n\n...anyway, back to game 1.\nPlayer 2 wins round 9 of game 1!\n"));
+        assert!(text.ends_with("Player 2 wins round 17 of game 1!\nThe winner of game 1 is player 2!\n\n\n== Post-game results ==\nPlayer 1's deck: \nPlayer 2's deck: 7, 5, 6, 2, 4, 1, 10, 8, 9, 3\n"));
+
+        let stats = game.stats();
+        assert_eq!(stats.games(), text.matches("=== Game").count());
+        assert_eq!(stats.sub_games(), text.matches("Playing a sub-game").count());
+        assert_eq!(stats.total_rounds(), text.matches("-- Round").count());
+        assert_eq!((stats.rounds[0], stats.loops, stats.memo_hits), (17, 0, 0));
+        assert!(stats.max_depth >= 1);
+
+        // Without the log repeated sub-games come from the memo instead
+        let logged_score = game.score();
+        let mut game = Game::with_rules(&decks, Rules::recursive_combat());
+        game.play();
+        assert!(game.log().is_none());
+        assert_eq!(game.score(), logged_score);
+        assert!(game.stats().total_rounds() <= stats.total_rounds());
+
+        let decks = parse_decks(&std::fs::read_to_string("res/22/input_inf.txt").unwrap()).unwrap();
+        let mut game = Game::with_rules(&decks, Rules::recursive_combat());
+        game.enable_log();
+        game.play();
+        let log = game.log().unwrap();
+        assert!(log.iter().any(|event| matches!(event, Event::Loop { game: 1, winner: 0, .. })));
+        assert_eq!(game.stats().loops, 1);
+    }
+
+    #[test]
+    fn monte_carlo() {
+        let mut rng = Rng::new(2020);
+        let decks = deal(&mut rng, 10, 3).unwrap();
+        assert_eq!(Some(decks.clone()), deal(&mut Rng::new(2020), 10, 3));
+        assert_eq!(decks.iter().map(|d| d.len()).collect::<Vec<_>>(), vec![4, 3, 3]);
+        let mut cards: Vec<usize> = decks.iter().flatten().copied().collect();
+        cards.sort_unstable();
+        assert_eq!(cards, (1..=10).collect::<Vec<_>>());
+        assert!((0..1000).all(|_| rng.below(7).unwrap() < 7));
+        assert_eq!(rng.below(1), Some(0));
+        assert_eq!(rng.below(0), None);
+        assert_eq!(deal(&mut rng, 10, 0), None);
+
+        let mut config = Study { seed: 22, games: 300, cards: 12, players: 2, rules: Rules::recursive_combat(), threads: 1 };
+        let report = study(&config).unwrap();
+        config.threads = 4;
+        assert_eq!(study(&config), Some(report.clone()));
+        assert_eq!(report.wins.iter().sum::<usize>() + report.no_winner, 300);
+        assert_eq!(report.lengths.values().sum::<usize>(), 300);
+        assert!(report.sub_games > 0 && report.looped <= report.loops);
+        println!("{:?} mean length {:.2}", report, report.mean_length());
+
+        config.rules = Rules::combat();
+        config.players = 3;
+        let report = study(&config).unwrap();
+        assert_eq!((report.wins.len(), report.sub_games), (3, 0));
+        println!("{:?} mean length {:.2}", report, report.mean_length());
+
+        config.players = 0;
+        assert_eq!(study(&config), None);
+    }
+
+    #[test]
+    fn bad_decks() {
+        assert_eq!(parse_decks("Player 1:\n3\n\nPlayer 2:\n1\n2\n"), Ok(vec![vec![3].into(), vec![1, 2].into()]));
+        assert_eq!(parse_decks(""), Ok(vec![]));
+        assert_eq!(parse_decks("3\nPlayer 1:\n1\n"), Err(DeckError::NoPlayer { line: 0 }));
+        assert_eq!(parse_decks("Player 1:\n1\nten\n"), Err(DeckError::BadCard { line: 2 }));
+    }
+
     #[test]
     fn water() {
         drive("res/22/input_inf.txt");
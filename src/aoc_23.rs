@@ -2,11 +2,16 @@ use std::fmt::{Formatter, Error};
 
 #[derive(Debug)]
 pub struct Cups {
-    next_list: Vec<usize>,
-    head: usize,
+    // Label of the cup clockwise of each label, 0 is unused. u32 keeps a
+    // hundred million cups to 400MB.
+    next_list: Vec<u32>,
+    head: u32,
+    pick_up: usize,
+    // Scratch space for turn, so it doesn't allocate
+    picked: Vec<u32>,
 }
 
-fn mod_sub(num: usize, len: usize) -> usize {
+fn mod_sub(num: u32, len: u32) -> u32 {
     if num == 1 {
         len
     } else {
@@ -14,12 +19,48 @@ fn mod_sub(num: usize, len: usize) -> usize {
     }
 }
 
+// Saved positions, for Cups::restore
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Snapshot {
+    next_list: Vec<u32>,
+    head: u32,
+}
+
+// The circle came back to a position it was in before
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cycle {
+    // Moves before the repeated position was first seen
+    pub start: usize,
+    pub period: usize,
+}
+
+// Labels clockwise round the circle, once
+pub struct CupIter<'a> {
+    cups: &'a Cups,
+    next: u32,
+    left: usize,
+}
+
+impl Iterator for CupIter<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        let label = self.next;
+        self.next = self.cups.next_list[label as usize];
+        Some(label as usize)
+    }
+}
+
 impl std::fmt::Display for Cups {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         let mut curr = self.head;
         loop {
             write!(f, "{} ", curr)?;
-            curr = self.next_list[curr];
+            curr = self.next_list[curr as usize];
             if curr == self.head {
                 break;
             }
@@ -30,48 +71,171 @@ impl std::fmt::Display for Cups {
 
 impl Cups {
     pub fn new(nums: &[usize]) -> Self {
-        let mut next_list = vec![0; 10];
-        for i in 0..8 {
-            next_list[nums[i]] = nums[i+1];
+        Self::with_labels(nums, nums.len(), 3).unwrap()
+    }
+
+    // Cups labelled 1..=total_cups, starting with labels in that order and
+    // then the rest counting up. None if the labels don't fit, repeat, or
+    // leave nowhere to put the cups picked up.
+    pub fn with_labels(labels: &[usize], total_cups: usize, pick_up: usize) -> Option<Self> {
+        if total_cups > u32::MAX as usize || pick_up + 1 >= total_cups.max(1) {
+            return None;
+        }
+        let mut seen = vec![false; total_cups + 1];
+        for label in labels.iter() {
+            if *label == 0 || *label > total_cups || seen[*label] {
+                return None;
+            }
+            seen[*label] = true;
         }
-        next_list[nums[8]] = nums[0];
-        Self {
+
+        let order = labels.iter().copied().chain((1..=total_cups).filter(|label| !seen[*label]));
+        let mut next_list = vec![0; total_cups + 1];
+        let mut first = None;
+        let mut prev: Option<usize> = None;
+        for label in order {
+            match prev {
+                Some(prev) => next_list[prev] = label as u32,
+                None => first = Some(label),
+            }
+            prev = Some(label);
+        }
+        next_list[prev?] = first? as u32;
+
+        Some(Self {
             next_list,
-            head: nums[0]
+            head: first? as u32,
+            pick_up,
+            picked: Vec::with_capacity(pick_up),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.next_list.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Adds cups up to total_cups, after the one before the current cup
+    pub fn extend(&mut self, total_cups: usize) {
+        let old_len = self.len();
+        if total_cups <= old_len {
+            return;
         }
+        let last = self.next_list.iter().position(|next| *next == self.head).unwrap();
+        self.next_list.extend((old_len + 2..=total_cups + 1).map(|label| label as u32));
+        self.next_list[last] = old_len as u32 + 1;
+        self.next_list[total_cups] = self.head;
     }
 
     pub fn extend_pt_2(&mut self) {
-        // Get the last value:
-        let mut curr = self.head;
-        for _ in 0..8 {
-            curr = self.next_list[curr];
+        self.extend(1_000_000);
+    }
+
+    pub fn turn(&mut self) {
+        if self.pick_up == 0 {
+            self.head = self.next_list[self.head as usize];
+            return;
         }
-        for i in 10..=999999 {
-            self.next_list.push(i+1);
+
+        self.picked.clear();
+        let mut last = self.head;
+        for _ in 0..self.pick_up {
+            last = self.next_list[last as usize];
+            self.picked.push(last);
         }
-        self.next_list[curr] = 10;
-        self.next_list.push(self.head);
+
+        let max = self.len() as u32;
+        let mut dest_cup = mod_sub(self.head, max);
+        while self.picked.contains(&dest_cup) {
+            dest_cup = mod_sub(dest_cup, max);
+        }
+
+        let (head, last, dest_cup) = (self.head as usize, last as usize, dest_cup as usize);
+        let first = self.next_list[head];
+        self.next_list[head] = self.next_list[last];
+        self.next_list[last] = self.next_list[dest_cup];
+        self.next_list[dest_cup] = first;
+
+        self.head = self.next_list[head];
     }
 
-    pub fn turn(&mut self) {
-        let c1 = self.next_list[self.head];
-        let c2 = self.next_list[c1];
-        let c3 = self.next_list[c2];
-
-        let mut dest_cup = mod_sub(self.head, self.next_list.len() - 1);
-        for _ in 0..3 {
-            if dest_cup == c1 || dest_cup == c2 || dest_cup == c3 {
-                dest_cup = mod_sub(dest_cup, self.next_list.len() - 1);
-            }
+    pub fn part_1(labels: &[usize]) -> Option<String> {
+        let mut cups = Self::with_labels(labels, labels.len(), 3)?;
+        cups.run(100);
+        Some(cups.order_after_one())
+    }
+
+    pub fn part_2(labels: &[usize]) -> Option<u64> {
+        let mut cups = Self::with_labels(labels, 1_000_000, 3)?;
+        cups.run(10_000_000);
+        cups.product_after(1, 2)
+    }
+
+    pub fn run(&mut self, moves: usize) {
+        for _ in 0..moves {
+            self.turn();
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.head as usize
+    }
+
+    // Every label once, starting at label. None if there's no such cup.
+    pub fn iter_from(&self, label: usize) -> Option<CupIter<'_>> {
+        if label == 0 || label > self.len() {
+            return None;
         }
+        Some(CupIter { cups: self, next: label as u32, left: self.len() })
+    }
 
-        let temp = self.next_list[dest_cup];
-        self.next_list[dest_cup] = c1;
-        self.next_list[self.head] = self.next_list[c3];
-        self.next_list[c3] = temp;
+    // The k labels clockwise of label
+    pub fn labels_after(&self, label: usize, k: usize) -> Option<Vec<usize>> {
+        Some(self.iter_from(label)?.skip(1).take(k).collect())
+    }
+
+    pub fn product_after(&self, label: usize, k: usize) -> Option<u64> {
+        Some(self.labels_after(label, k)?.iter().map(|l| *l as u64).product())
+    }
 
-        self.head = self.next_list[self.head];
+    // Part 1's answer, the labels after cup 1 run together
+    pub fn order_after_one(&self) -> String {
+        self.labels_after(1, self.len() - 1).unwrap().iter().map(|l| l.to_string()).collect()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { next_list: self.next_list.clone(), head: self.head }
+    }
+
+    // Fails if the snapshot is of a different number of cups
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Option<()> {
+        if snapshot.next_list.len() != self.next_list.len() {
+            return None;
+        }
+        self.next_list.clone_from(&snapshot.next_list);
+        self.head = snapshot.head;
+        Some(())
+    }
+
+    // Plays up to max_moves, looking for a position that comes round again.
+    // Keeps every position, so only for small circles. Leaves the cups as
+    // they were.
+    pub fn find_cycle(&mut self, max_moves: usize) -> Option<Cycle> {
+        let saved = self.snapshot();
+        let mut seen = std::collections::HashMap::new();
+        let mut found = None;
+        for moves in 0..=max_moves {
+            if let Some(start) = seen.insert(self.snapshot(), moves) {
+                found = Some(Cycle { start, period: moves - start });
+                break;
+            }
+            self.turn();
+        }
+        self.restore(&saved);
+        found
     }
 }
 
@@ -83,19 +247,98 @@ mod tests {
         let mut cups = Cups::new(input);
 
         println!("Starting:  {}", cups);
-        for _ in 0..100 {
-            cups.turn();
-        }
+        cups.run(100);
         println!("After 100: {}", cups);
+        println!("Part 1: {}", Cups::part_1(input).unwrap());
+        println!("Part 2: {}", Cups::part_2(input).unwrap());
+    }
 
-        let mut cups = Cups::new(input);
-        cups.extend_pt_2();
-        for _ in 0..10000000 {
+    // The moves done on a plain Vec, current cup always first
+    fn naive(labels: &[usize], total_cups: usize, pick_up: usize, moves: usize) -> Vec<usize> {
+        let mut circle: Vec<usize> = labels.to_vec();
+        circle.extend((1..=total_cups).filter(|label| !labels.contains(label)));
+        for _ in 0..moves {
+            let current = circle[0];
+            let picked: Vec<usize> = circle.drain(1..=pick_up).collect();
+            let mut dest = current;
+            loop {
+                dest = if dest == 1 { total_cups } else { dest - 1 };
+                if !picked.contains(&dest) {
+                    break;
+                }
+            }
+            let at = circle.iter().position(|label| *label == dest).unwrap() + 1;
+            circle.splice(at..at, picked);
+            circle.rotate_left(1);
+        }
+        circle
+    }
+
+    #[test]
+    fn any_size() {
+        for (labels, total_cups, pick_up) in [
+            (&[3, 8, 9, 1, 2, 5, 4, 6, 7][..], 9, 3),
+            (&[3, 8, 9, 1, 2, 5, 4, 6, 7][..], 20, 3),
+            (&[5, 2][..], 13, 1),
+            (&[4, 1, 7][..], 11, 5),
+            (&[][..], 6, 4),
+            (&[2, 1][..], 2, 0),
+        ].iter() {
+            let mut cups = Cups::with_labels(labels, *total_cups, *pick_up).unwrap();
+            for moves in 0..50 {
+                let expected: Vec<String> = naive(labels, *total_cups, *pick_up, moves).iter().map(|l| format!("{} ", l)).collect();
+                assert_eq!(cups.to_string(), expected.concat(), "{:?} after {}", labels, moves);
+                cups.turn();
+            }
+        }
+
+        let mut cups = Cups::new(&[3, 8, 9, 1, 2, 5, 4, 6, 7]);
+        cups.extend(20);
+        assert_eq!(cups.to_string(), Cups::with_labels(&[3, 8, 9, 1, 2, 5, 4, 6, 7], 20, 3).unwrap().to_string());
+        assert_eq!(cups.len(), 20);
+
+        assert!(Cups::with_labels(&[1, 2, 2], 5, 1).is_none());
+        assert!(Cups::with_labels(&[1, 6], 5, 1).is_none());
+        assert!(Cups::with_labels(&[0], 5, 1).is_none());
+        assert!(Cups::with_labels(&[1, 2, 3], 4, 3).is_none());
+        assert!(Cups::with_labels(&[], 0, 0).is_none());
+    }
+
+    #[test]
+    fn queries() {
+        let example = [3, 8, 9, 1, 2, 5, 4, 6, 7];
+        let mut cups = Cups::new(&example);
+        cups.run(10);
+        assert_eq!(cups.order_after_one(), "92658374");
+        assert_eq!(cups.iter_from(5).unwrap().collect::<Vec<_>>(), vec![5, 8, 3, 7, 4, 1, 9, 2, 6]);
+        assert_eq!(cups.current(), 8);
+        assert_eq!(cups.labels_after(1, 2), Some(vec![9, 2]));
+        assert_eq!(cups.product_after(1, 2), Some(18));
+        assert!(cups.iter_from(10).is_none() && cups.iter_from(0).is_none());
+        assert_eq!(Cups::part_1(&example), Some(String::from("67384529")));
+
+        let saved = cups.snapshot();
+        cups.run(90);
+        assert_eq!(cups.order_after_one(), "67384529");
+        cups.restore(&saved).unwrap();
+        assert_eq!(cups.order_after_one(), "92658374");
+        assert!(Cups::new(&[2, 1, 3, 4, 5]).restore(&saved).is_none());
+
+        // Five cups only have so many positions, so one has to repeat
+        let mut cups = Cups::with_labels(&[1, 2, 3, 4, 5], 5, 1).unwrap();
+        let cycle = cups.find_cycle(1000).unwrap();
+        // The starting position itself comes back after 13 moves
+        assert_eq!(cycle, Cycle { start: 0, period: 13 });
+        assert_eq!(cups.order_after_one(), "2345");
+        cups.run(cycle.start);
+        let first = cups.snapshot();
+        for _ in 1..cycle.period {
             cups.turn();
+            assert_ne!(cups.snapshot(), first);
         }
-        let v1 = cups.next_list[1];
-        let v2 = cups.next_list[v1];
-        println!("After 10000000: {} {} {}", v1, v2, v1 * v2);
+        cups.turn();
+        assert_eq!(cups.snapshot(), first);
+        assert!(Cups::new(&example).find_cycle(3).is_none());
     }
 
     #[test]
@@ -1,33 +1,54 @@
+use crate::modarith::{discrete_log, pow_mod};
 
-pub fn do_it(n: usize, m: usize) -> usize {
-    let subject = 7;
-    let base = 20201227;
-
-    let mut m_count = 0;
-    let mut n_count = 0;
-    let mut acc = 1;
-    let mut count = 1;
-    while m_count == 0 || n_count == 0 {
-        acc *= subject;
-        acc %= base;
-        if acc == n {
-            assert_eq!(n_count, 0);
-            n_count = count;
-        }
-        if acc == m {
-            assert_eq!(m_count, 0);
-            m_count = count;
-        }
-        count += 1;
+pub const SUBJECT: u64 = 7;
+pub const MODULUS: u64 = 20201227;
+
+// One end of the handshake, the card or the door. The loop size is its
+// secret, and everything else is public.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    subject: u64,
+    modulus: u64,
+    secret_loop_size: u64,
+}
+
+impl Device {
+    pub fn new(secret_loop_size: u64) -> Self {
+        Self::with_params(SUBJECT, MODULUS, secret_loop_size)
+    }
+
+    pub fn with_params(subject: u64, modulus: u64, secret_loop_size: u64) -> Self {
+        Self { subject, modulus, secret_loop_size }
+    }
+
+    // The subject transformed secret_loop_size times
+    pub fn public_key(&self) -> u64 {
+        pow_mod(self.subject, self.secret_loop_size, self.modulus)
     }
 
-    acc = m;
-    for _ in 1..n_count {
-        acc *= m;
-        acc %= base;
+    // The other side's public key transformed secret_loop_size times
+    pub fn encryption_key(&self, other_public: u64) -> u64 {
+        pow_mod(other_public, self.secret_loop_size, self.modulus)
     }
+}
 
-    acc
+// The attack: a device with the same public key as the one overheard. Its
+// loop size needn't be the real one, any that gives the public key derives
+// the same encryption keys. Only as hard as a discrete log, which is why the
+// modulus has to be big.
+pub fn forge(subject: u64, modulus: u64, public_key: u64) -> Option<Device> {
+    let loop_size = discrete_log(subject, public_key, modulus)?;
+    Some(Device::with_params(subject, modulus, loop_size))
+}
+
+// The encryption key both sides of the handshake arrive at, found from the
+// public keys alone. None if card_pub isn't a power of the subject.
+pub fn handshake(subject: u64, modulus: u64, card_pub: u64, door_pub: u64) -> Option<u64> {
+    Some(forge(subject, modulus, card_pub)?.encryption_key(door_pub))
+}
+
+pub fn do_it(n: usize, m: usize) -> usize {
+    handshake(SUBJECT, MODULUS, n as u64, m as u64).unwrap() as usize
 }
 
 #[cfg(test)]
@@ -39,6 +60,45 @@ mod tests {
         println!("Part 1: {}", val);
     }
 
+    #[test]
+    fn large_modulus() {
+        // Loop sizes far past anything a linear search would reach
+        let (subject, modulus) = (3, 1_099_511_627_791);
+        let (card_loop, door_loop) = (987_654_321_012, 123_456_789_012);
+        let card_pub = pow_mod(subject, card_loop, modulus);
+        let door_pub = pow_mod(subject, door_loop, modulus);
+        assert_eq!(handshake(subject, modulus, card_pub, door_pub), Some(pow_mod(door_pub, card_loop, modulus)));
+
+        assert_eq!(do_it(5764801, 17807724), 14897079);
+        assert_eq!(handshake(7, 20201227, 5764801, 17807724), handshake(7, 20201227, 17807724, 5764801));
+    }
+
+    #[test]
+    fn protocol() {
+        // The puzzle's example loop sizes
+        let card = Device::new(8);
+        let door = Device::new(11);
+        assert_eq!((card.public_key(), door.public_key()), (5764801, 17807724));
+        assert_eq!(card.encryption_key(door.public_key()), 14897079);
+        assert_eq!(door.encryption_key(card.public_key()), 14897079);
+
+        for (card_loop, door_loop) in [(1, 1), (20201226, 3), (123_456, 7_654_321)].iter() {
+            let card = Device::new(*card_loop);
+            let door = Device::new(*door_loop);
+            let key = card.encryption_key(door.public_key());
+            assert_eq!(door.encryption_key(card.public_key()), key);
+
+            // All the eavesdropper sees is the two public keys
+            let fake_card = forge(SUBJECT, MODULUS, card.public_key()).unwrap();
+            assert_eq!(fake_card.public_key(), card.public_key());
+            assert_eq!(fake_card.encryption_key(door.public_key()), key);
+            assert_eq!(handshake(SUBJECT, MODULUS, card.public_key(), door.public_key()), Some(key));
+        }
+
+        // 2 only ever reaches half the residues mod 7, so some keys are never public
+        assert_eq!(forge(2, 7, 3), None);
+    }
+
     #[test]
     fn it_works() {
         drive(5764801,17807724);
@@ -0,0 +1,363 @@
+use std::convert::TryFrom;
+use std::cmp::Ordering;
+use std::ops::{Add, Mul, Neg, Sub};
+
+// Arbitrary precision signed integer. Magnitude is stored as little endian
+// base 2^32 limbs with no trailing zero limbs, so zero is an empty vec.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct BigInt {
+    negative: bool,
+    mag: Vec<u32>,
+}
+
+fn trim(mut mag: Vec<u32>) -> Vec<u32> {
+    while mag.last() == Some(&0) {
+        mag.pop();
+    }
+    mag
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    if a.len() != b.len() {
+        return a.len().cmp(&b.len());
+    }
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x.cmp(y);
+        }
+    }
+    Ordering::Equal
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        out.push(sum as u32);
+        carry = sum >> 32;
+    }
+    out.push(carry as u32);
+    trim(out)
+}
+
+// a - b, where a >= b
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, x) in a.iter().enumerate() {
+        let mut diff = *x as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        out.push(diff as u32);
+    }
+    trim(out)
+}
+
+fn mul_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = vec![0u32; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, y) in b.iter().enumerate() {
+            let cur = out[i + j] as u64 + *x as u64 * *y as u64 + carry;
+            out[i + j] = cur as u32;
+            carry = cur >> 32;
+        }
+        out[i + b.len()] = carry as u32;
+    }
+    trim(out)
+}
+
+// mag * mul + add, for small mul and add
+fn mul_small_add(mag: &[u32], mul: u32, add: u32) -> Vec<u32> {
+    let mut out = Vec::with_capacity(mag.len() + 1);
+    let mut carry = add as u64;
+    for x in mag.iter() {
+        let cur = *x as u64 * mul as u64 + carry;
+        out.push(cur as u32);
+        carry = cur >> 32;
+    }
+    out.push(carry as u32);
+    trim(out)
+}
+
+fn div_small(mag: &[u32], div: u32) -> (Vec<u32>, u32) {
+    let mut out = vec![0u32; mag.len()];
+    let mut rem = 0u64;
+    for (i, x) in mag.iter().enumerate().rev() {
+        let cur = (rem << 32) | *x as u64;
+        out[i] = (cur / div as u64) as u32;
+        rem = cur % div as u64;
+    }
+    (trim(out), rem as u32)
+}
+
+fn shl1_or(mag: &[u32], bit: u32) -> Vec<u32> {
+    let mut out = Vec::with_capacity(mag.len() + 1);
+    let mut carry = bit;
+    for x in mag.iter() {
+        out.push((x << 1) | carry);
+        carry = x >> 31;
+    }
+    out.push(carry);
+    trim(out)
+}
+
+// Schoolbook binary long division, one bit of the quotient at a time
+fn div_mag(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if b.len() == 1 {
+        let (q, r) = div_small(a, b[0]);
+        return (q, trim(vec![r]));
+    }
+
+    let mut quot = vec![0u32; a.len()];
+    let mut rem = vec![];
+    for i in (0..a.len() * 32).rev() {
+        rem = shl1_or(&rem, (a[i / 32] >> (i % 32)) & 1);
+        if cmp_mag(&rem, b) != Ordering::Less {
+            rem = sub_mag(&rem, b);
+            quot[i / 32] |= 1 << (i % 32);
+        }
+    }
+    (trim(quot), rem)
+}
+
+impl BigInt {
+    fn from_parts(negative: bool, mag: Vec<u32>) -> Self {
+        let mag = trim(mag);
+        Self { negative: negative && !mag.is_empty(), mag }
+    }
+
+    pub fn zero() -> Self {
+        Self::default()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mag.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn abs(&self) -> Self {
+        Self::from_parts(false, self.mag.clone())
+    }
+
+    // Quotient rounded toward zero and the remainder, which takes the sign of
+    // self, same as the primitive integer types. None when dividing by zero.
+    pub fn div_rem(&self, other: &Self) -> Option<(Self, Self)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (q, r) = div_mag(&self.mag, &other.mag);
+        Some((
+            Self::from_parts(self.negative != other.negative, q),
+            Self::from_parts(self.negative, r),
+        ))
+    }
+
+    pub fn pow(&self, mut exp: u32) -> Self {
+        let mut base = self.clone();
+        let mut acc = Self::from(1u64);
+        while exp > 0 {
+            if exp & 1 != 0 {
+                acc = &acc * &base;
+            }
+            base = &base * &base;
+            exp >>= 1;
+        }
+        acc
+    }
+
+    pub fn to_i128(&self) -> Option<i128> {
+        if self.mag.len() > 4 {
+            return None;
+        }
+        let mag = self.mag.iter().rev().fold(0u128, |acc, x| (acc << 32) | *x as u128);
+        if self.negative {
+            0i128.checked_sub_unsigned(mag)
+        } else {
+            i128::try_from(mag).ok()
+        }
+    }
+
+    // Remainder on division by a small modulus, always non-negative
+    pub fn rem_u64(&self, modulus: u64) -> u64 {
+        let rem = self.mag.iter().rev().fold(0u128, |acc, x| ((acc << 32) | *x as u128) % modulus as u128) as u64;
+        if self.negative && rem != 0 {
+            modulus - rem
+        } else {
+            rem
+        }
+    }
+}
+
+impl From<u64> for BigInt {
+    fn from(val: u64) -> Self {
+        Self::from_parts(false, vec![val as u32, (val >> 32) as u32])
+    }
+}
+
+impl From<i128> for BigInt {
+    fn from(val: i128) -> Self {
+        let mag = val.unsigned_abs();
+        Self::from_parts(val < 0, (0..4).map(|i| (mag >> (32 * i)) as u32).collect())
+    }
+}
+
+impl From<usize> for BigInt {
+    fn from(val: usize) -> Self {
+        Self::from(val as u64)
+    }
+}
+
+impl std::str::FromStr for BigInt {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(());
+        }
+
+        let mag = digits.bytes().fold(vec![], |mag, b| mul_small_add(&mag, 10, (b - b'0') as u32));
+        Ok(Self::from_parts(negative, mag))
+    }
+}
+
+impl std::fmt::Display for BigInt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+
+        // Peel off 9 decimal digits at a time
+        let mut chunks = vec![];
+        let mut mag = self.mag.clone();
+        while !mag.is_empty() {
+            let (q, r) = div_small(&mag, 1_000_000_000);
+            chunks.push(r);
+            mag = q;
+        }
+
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.mag, &other.mag),
+            (true, true) => cmp_mag(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: Self) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::from_parts(self.negative, add_mag(&self.mag, &other.mag));
+        }
+        match cmp_mag(&self.mag, &other.mag) {
+            Ordering::Less => BigInt::from_parts(other.negative, sub_mag(&other.mag, &self.mag)),
+            _ => BigInt::from_parts(self.negative, sub_mag(&self.mag, &other.mag)),
+        }
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::from_parts(!self.negative, self.mag.clone())
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: Self) -> BigInt {
+        self + &(-other)
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: Self) -> BigInt {
+        BigInt::from_parts(self.negative != other.negative, mul_mag(&self.mag, &other.mag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_i128() {
+        let vals: [i128; 9] = [0, 1, -1, 7, -13, 4294967295, 4294967296, -98765432101234, 170141183460469231731687303715884105];
+        for a in vals.iter() {
+            for b in vals.iter() {
+                let (x, y) = (BigInt::from(*a), BigInt::from(*b));
+                assert_eq!((&x + &y).to_i128(), a.checked_add(*b));
+                assert_eq!((&x - &y).to_i128(), a.checked_sub(*b));
+                if let Some(prod) = a.checked_mul(*b) {
+                    assert_eq!((&x * &y).to_i128(), Some(prod));
+                }
+                if *b != 0 {
+                    let (q, r) = x.div_rem(&y).unwrap();
+                    assert_eq!((q.to_i128(), r.to_i128()), (Some(a / b), Some(a % b)));
+                }
+                assert_eq!(x.cmp(&y), a.cmp(b));
+            }
+        }
+        assert_eq!(BigInt::from(5u64).div_rem(&BigInt::zero()), None);
+    }
+
+    #[test]
+    fn big_values() {
+        let fact = (1..=30u64).fold(BigInt::from(1u64), |acc, i| &acc * &BigInt::from(i));
+        assert_eq!(fact.to_string(), "265252859812191058636308480000000");
+        assert_eq!(fact.to_i128(), Some(265252859812191058636308480000000));
+
+        let big = BigInt::from(2u64).pow(200);
+        assert_eq!(big.to_string(), "1606938044258990275541962092341162602522202993782792835301376");
+        assert_eq!(big.to_i128(), None);
+        assert_eq!(big.rem_u64(1_000_000_007), 2u128.pow(100).rem_euclid(1_000_000_007).pow(2) as u64 % 1_000_000_007);
+
+        let (q, r) = big.div_rem(&fact).unwrap();
+        assert_eq!(&(&q * &fact) + &r, big);
+        assert!(r < fact);
+
+        for s in ["0", "-1", "123456789012345678901234567890", "-1000000000000000000000000000000000001"].iter() {
+            assert_eq!(s.parse::<BigInt>().unwrap().to_string(), *s);
+        }
+        assert_eq!("-0".parse::<BigInt>(), Ok(BigInt::zero()));
+        assert!("12a".parse::<BigInt>().is_err());
+        assert_eq!(BigInt::from(-7i128).rem_u64(5), 3);
+    }
+}
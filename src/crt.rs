@@ -0,0 +1,159 @@
+use crate::modarith::ext_gcd;
+use crate::num::Integer;
+
+// Chinese remainder theorem: the x with x = residue (mod modulus) for every
+// congruence at once. Moduli don't have to be coprime.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrtError {
+    // The congruence at this index has a modulus below 1
+    BadModulus(usize),
+    // The congruence at this index contradicts the ones before it
+    Inconsistent(usize),
+    // An intermediate value didn't fit the integer type
+    Overflow,
+}
+
+// Every solution is value + k * period
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Solution<T> {
+    // The smallest non-negative one
+    pub value: T,
+    // Least common multiple of the moduli
+    pub period: T,
+}
+
+fn rem_euclid<T: Integer>(a: &T, m: &T) -> Option<T> {
+    let r = a.checked_rem(m)?;
+    if r < T::from_usize(0)? {
+        r.checked_add(m)
+    } else {
+        Some(r)
+    }
+}
+
+// a + b reduced modulo m, for a and b already reduced, without going past m
+fn add_rem<T: Integer>(a: &T, b: &T, m: &T) -> Option<T> {
+    let gap = m.checked_sub(b)?;
+    if *a >= gap {
+        a.checked_sub(&gap)
+    } else {
+        a.checked_add(b)
+    }
+}
+
+// a * b reduced modulo m. When the reduced product is still too big, adds
+// up doublings of a instead, which never goes past 2m.
+fn mul_rem<T: Integer>(a: &T, b: &T, m: &T) -> Option<T> {
+    let (mut a, mut b) = (rem_euclid(a, m)?, rem_euclid(b, m)?);
+    if let Some(product) = a.checked_mul(&b) {
+        return rem_euclid(&product, m);
+    }
+    let (zero, two) = (T::from_usize(0)?, T::from_usize(2)?);
+    let mut acc = zero.clone();
+    while b != zero {
+        if b.checked_rem(&two)? != zero {
+            acc = add_rem(&acc, &a, m)?;
+        }
+        a = add_rem(&a, &a, m)?;
+        b = b.checked_div(&two)?;
+    }
+    Some(acc)
+}
+
+// Folds the congruences in one at a time. Merging x = a1 (mod m1) with
+// x = a2 (mod m2) needs a2 - a1 to be a multiple of g = gcd(m1, m2), and
+// then leaves x = a1 + m1 * t (mod lcm), where t solves
+// (m1 / g) * t = (a2 - a1) / g (mod m2 / g).
+pub fn solve<T: Integer>(congruences: &[(T, T)]) -> Result<Solution<T>, CrtError> {
+    let constant = |val| T::from_usize(val).ok_or(CrtError::Overflow);
+    let mut value = constant(0)?;
+    let mut period = constant(1)?;
+    for (i, (residue, modulus)) in congruences.iter().enumerate() {
+        if *modulus < constant(1)? {
+            return Err(CrtError::BadModulus(i));
+        }
+        let residue = rem_euclid(residue, modulus).ok_or(CrtError::Overflow)?;
+
+        let (g, inv, _) = ext_gcd(&period, modulus).ok_or(CrtError::Overflow)?;
+        let diff = residue.checked_sub(&value).ok_or(CrtError::Overflow)?;
+        if diff.checked_rem(&g).ok_or(CrtError::Overflow)? != constant(0)? {
+            return Err(CrtError::Inconsistent(i));
+        }
+        let quot = diff.checked_div(&g).ok_or(CrtError::Overflow)?;
+
+        let step = modulus.checked_div(&g).ok_or(CrtError::Overflow)?;
+        let t = mul_rem(&quot, &inv, &step).ok_or(CrtError::Overflow)?;
+
+        let next_period = period.checked_mul(&step).ok_or(CrtError::Overflow)?;
+        let next_value = period.checked_mul(&t).and_then(|x| x.checked_add(&value)).ok_or(CrtError::Overflow)?;
+        value = rem_euclid(&next_value, &next_period).ok_or(CrtError::Overflow)?;
+        period = next_period;
+    }
+    Ok(Solution { value, period })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bigint::BigInt;
+
+    #[test]
+    fn small() {
+        // The day-13 example, bus id m leaving i minutes after x
+        let buses = [(7i128, 0i128), (13, 1), (59, 4), (31, 6), (19, 7)];
+        let congruences: Vec<(i128, i128)> = buses.iter().map(|(m, i)| (-i, *m)).collect();
+        assert_eq!(solve(&congruences), Ok(Solution { value: 1068781, period: 7 * 13 * 59 * 31 * 19 }));
+
+        // Shared factors
+        assert_eq!(solve(&[(2i128, 6), (8, 9)]), Ok(Solution { value: 8, period: 18 }));
+        assert_eq!(solve(&[(3i128, 4), (3, 4), (1, 2)]), Ok(Solution { value: 3, period: 4 }));
+        assert_eq!(solve(&[(1i128, 4), (2, 6)]), Err(CrtError::Inconsistent(1)));
+        assert_eq!(solve(&[(1i128, 4), (2, 0)]), Err(CrtError::BadModulus(1)));
+        assert_eq!(solve::<i128>(&[]), Ok(Solution { value: 0, period: 1 }));
+
+        // Brute force over every small system
+        for (m1, m2) in [(4i128, 6i128), (5, 7), (12, 18), (1, 9)].iter() {
+            for a1 in 0..*m1 {
+                for a2 in 0..*m2 {
+                    let brute = (0..m1 * m2).find(|x| x % m1 == a1 && x % m2 == a2);
+                    let solved = solve(&[(a1, *m1), (a2, *m2)]).ok().map(|s| s.value);
+                    assert_eq!(solved, brute, "{} {} {} {}", a1, m1, a2, m2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn big() {
+        // Mersenne primes, whose product is past i128
+        let m1 = (1i128 << 61) - 1;
+        let m2 = (1i128 << 89) - 1;
+        let congruences = [(12345i128, m1), (-678, m2)];
+        assert_eq!(solve(&congruences), Err(CrtError::Overflow));
+
+        let to_big = |congruences: &[(i128, i128)]| -> Vec<(BigInt, BigInt)> {
+            congruences.iter().map(|(a, m)| (BigInt::from(*a), BigInt::from(*m))).collect()
+        };
+
+        // A modulus past 2^64, so multiplying residues would overflow, but
+        // with a product of moduli that still fits
+        let fits = [(12345i128, (1i128 << 61) - 1), (-678, (1 << 65) + 1)];
+        let solution = solve(&fits).unwrap();
+        assert_eq!(solution.period, ((1 << 61) - 1) * ((1 << 65) + 1));
+        let big_solution = solve(&to_big(&fits)).unwrap();
+        assert_eq!(big_solution.value.to_i128(), Some(solution.value));
+        for (a, m) in fits.iter() {
+            assert_eq!((solution.value - a).rem_euclid(*m), 0);
+        }
+
+        let big = to_big(&congruences);
+        let solution = solve(&big).unwrap();
+        assert_eq!(solution.period, &big[0].1 * &big[1].1);
+        assert!(solution.value < solution.period && !solution.value.is_negative());
+        for (a, m) in big.iter() {
+            let diff = &solution.value - a;
+            assert!(diff.div_rem(m).unwrap().1.is_zero());
+        }
+    }
+}
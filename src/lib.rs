@@ -23,3 +23,7 @@ pub mod aoc_22;
 pub mod aoc_23;
 pub mod aoc_24;
 pub mod aoc_25;
+pub mod bigint;
+pub mod num;
+pub mod modarith;
+pub mod crt;
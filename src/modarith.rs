@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use crate::num::Integer;
+
+pub fn mul_mod(a: u64, b: u64, modulus: u64) -> u64 {
+    (a as u128 * b as u128 % modulus as u128) as u64
+}
+
+pub fn pow_mod(base: u64, mut exp: u64, modulus: u64) -> u64 {
+    let mut base = base % modulus;
+    let mut acc = 1 % modulus;
+    while exp > 0 {
+        if exp & 1 != 0 {
+            acc = mul_mod(acc, base, modulus);
+        }
+        base = mul_mod(base, base, modulus);
+        exp >>= 1;
+    }
+    acc
+}
+
+// (g, x, y) with a * x + b * y = g = gcd(a, b) and g >= 0. None if an
+// intermediate value overflows.
+pub fn ext_gcd<T: Integer>(a: &T, b: &T) -> Option<(T, T, T)> {
+    let (zero, one) = (T::from_usize(0)?, T::from_usize(1)?);
+    let (mut old_r, mut r) = (a.clone(), b.clone());
+    let (mut old_s, mut s) = (one.clone(), zero.clone());
+    let (mut old_t, mut t) = (zero.clone(), one);
+    while r != zero {
+        let q = old_r.checked_div(&r)?;
+        let next_r = old_r.checked_sub(&q.checked_mul(&r)?)?;
+        let next_s = old_s.checked_sub(&q.checked_mul(&s)?)?;
+        let next_t = old_t.checked_sub(&q.checked_mul(&t)?)?;
+        old_r = std::mem::replace(&mut r, next_r);
+        old_s = std::mem::replace(&mut s, next_s);
+        old_t = std::mem::replace(&mut t, next_t);
+    }
+    if old_r < zero {
+        Some((old_r.checked_neg()?, old_s.checked_neg()?, old_t.checked_neg()?))
+    } else {
+        Some((old_r, old_s, old_t))
+    }
+}
+
+// None when a shares a factor with the modulus
+pub fn inverse_mod(a: u64, modulus: u64) -> Option<u64> {
+    let (g, x, _) = ext_gcd(&(a as i128), &(modulus as i128))?;
+    if g != 1 {
+        return None;
+    }
+    Some(x.rem_euclid(modulus as i128) as u64)
+}
+
+// Smallest x with base^x = target, by baby-step giant-step. Takes about
+// sqrt(modulus) time and memory. The base has to be coprime to the modulus.
+pub fn bsgs(base: u64, target: u64, modulus: u64) -> Option<u64> {
+    let target = target % modulus;
+    if target == 1 % modulus {
+        return Some(0);
+    }
+    inverse_mod(base, modulus)?;
+
+    let n = (modulus as f64).sqrt().ceil() as u64 + 1;
+    // target * base^j -> largest such j, giving the smallest x below
+    let mut baby = HashMap::new();
+    let mut val = target;
+    for j in 0..n {
+        baby.insert(val, j);
+        val = mul_mod(val, base, modulus);
+    }
+
+    // base^(i * n) = target * base^j means x = i * n - j
+    let giant = pow_mod(base, n, modulus);
+    let mut val = 1 % modulus;
+    for i in 1..=n {
+        val = mul_mod(val, giant, modulus);
+        if let Some(j) = baby.get(&val) {
+            return Some(i * n - j);
+        }
+    }
+    None
+}
+
+// Some x with base^x = target, by Pollard's rho. Needs little memory, so
+// works where bsgs can't, but needs the order of the group, modulus - 1 for
+// a prime, and the answer is only reduced modulo that.
+pub fn pollard_rho_log(base: u64, target: u64, modulus: u64, order: u64) -> Option<u64> {
+    let target = target % modulus;
+    if target == 1 % modulus {
+        return Some(0);
+    }
+    let n = order as u128;
+
+    // Walk x = base^a * target^b, splitting on x % 3
+    let step = |(x, a, b): (u64, u128, u128)| match x % 3 {
+        0 => (mul_mod(x, x, modulus), a * 2 % n, b * 2 % n),
+        1 => (mul_mod(x, base, modulus), (a + 1) % n, b),
+        _ => (mul_mod(x, target, modulus), a, (b + 1) % n),
+    };
+
+    // Different starting points in case a walk collides uselessly
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    for _ in 0..32 {
+        seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+        let (a0, b0) = ((seed >> 11) as u128 % n, (seed >> 33) as u128 % n);
+        let start = (mul_mod(pow_mod(base, a0 as u64, modulus), pow_mod(target, b0 as u64, modulus), modulus), a0, b0);
+
+        // Floyd: tortoise and hare
+        let (mut slow, mut fast) = (step(start), step(step(start)));
+        while slow.0 != fast.0 {
+            slow = step(slow);
+            fast = step(step(fast));
+        }
+
+        // base^(a1 - a2) = target^(b2 - b1), so (b2 - b1) x = a1 - a2 mod n
+        let r = (slow.2 + n - fast.2) % n;
+        let s = (fast.1 + n - slow.1) % n;
+        let (g, inv, _) = ext_gcd(&(r as i128), &(n as i128))?;
+        if r == 0 || s as i128 % g != 0 {
+            continue;
+        }
+        let step_size = n / g as u128;
+        let x0 = (inv.rem_euclid(step_size as i128) as u128 * (s / g as u128)) % step_size;
+        for k in 0..g as u128 {
+            let x = (x0 + k * step_size) as u64;
+            if pow_mod(base, x, modulus) == target {
+                return Some(x);
+            }
+        }
+    }
+    None
+}
+
+// bsgs while its table stays a sensible size, otherwise Pollard's rho, which
+// then assumes the modulus is prime
+pub fn discrete_log(base: u64, target: u64, modulus: u64) -> Option<u64> {
+    if modulus <= 1 << 40 {
+        bsgs(base, target, modulus)
+    } else {
+        pollard_rho_log(base, target, modulus, modulus - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basics() {
+        let mut acc = 1;
+        for exp in 0..100 {
+            assert_eq!(pow_mod(7, exp, 20201227), acc);
+            acc = acc * 7 % 20201227;
+        }
+        assert_eq!(pow_mod(5, 0, 1), 0);
+        assert_eq!(pow_mod(u64::MAX, u64::MAX, (1 << 61) - 1), pow_mod(u64::MAX % ((1 << 61) - 1), u64::MAX, (1 << 61) - 1));
+
+        assert_eq!(ext_gcd(&240i128, &46), Some((2, -9, 47)));
+        assert_eq!(ext_gcd(&-240i128, &46), Some((2, 9, 47)));
+        assert_eq!(ext_gcd(&i128::MIN, &-1), None);
+        assert_eq!(inverse_mod(3, 7), Some(5));
+        assert_eq!(inverse_mod(6, 9), None);
+        for a in 1..97 {
+            assert_eq!(mul_mod(a, inverse_mod(a, 97).unwrap(), 97), 1);
+        }
+    }
+
+    #[test]
+    fn discrete_logs() {
+        // Smallest answers, checked against a linear search
+        for target in 1..200 {
+            let linear = (0..200).find(|x| pow_mod(3, *x, 200) == target);
+            assert_eq!(bsgs(3, target, 200), linear, "{}", target);
+        }
+        assert_eq!(bsgs(7, 5764801, 20201227), Some(8));
+        assert_eq!(bsgs(2, 3, 4), None);
+
+        let p = 1_000_000_007;
+        let target = pow_mod(5, 123_456_789, p);
+        assert_eq!(bsgs(5, target, p), Some(123_456_789));
+        let x = pollard_rho_log(5, target, p, p - 1).unwrap();
+        assert_eq!(pow_mod(5, x, p), target);
+
+        // Too big for a bsgs table
+        let p = 1_099_511_627_791;
+        let target = pow_mod(3, 987_654_321_012, p);
+        let x = discrete_log(3, target, p).unwrap();
+        assert_eq!(pow_mod(3, x, p), target);
+    }
+}
@@ -0,0 +1,99 @@
+use std::convert::TryFrom;
+use crate::bigint::BigInt;
+
+// Numeric types with checked arithmetic, None meaning overflow, division by
+// zero and the like.
+pub trait Num: Sized + Clone {
+    fn from_usize(val: usize) -> Option<Self>;
+    fn checked_add(&self, other: &Self) -> Option<Self>;
+    fn checked_sub(&self, other: &Self) -> Option<Self>;
+    fn checked_mul(&self, other: &Self) -> Option<Self>;
+    fn checked_div(&self, other: &Self) -> Option<Self>;
+    fn checked_pow(&self, exp: &Self) -> Option<Self>;
+    fn checked_neg(&self) -> Option<Self>;
+}
+
+// Plain integers, ordered, with division rounding toward zero
+pub trait Integer: Num + PartialOrd + std::fmt::Debug {
+    fn checked_rem(&self, other: &Self) -> Option<Self>;
+}
+
+macro_rules! impl_num_for_int {
+    ($t:ty) => {
+        impl Num for $t {
+            fn from_usize(val: usize) -> Option<Self> {
+                <$t>::try_from(val).ok()
+            }
+
+            fn checked_add(&self, other: &Self) -> Option<Self> {
+                <$t>::checked_add(*self, *other)
+            }
+
+            fn checked_sub(&self, other: &Self) -> Option<Self> {
+                <$t>::checked_sub(*self, *other)
+            }
+
+            fn checked_mul(&self, other: &Self) -> Option<Self> {
+                <$t>::checked_mul(*self, *other)
+            }
+
+            fn checked_div(&self, other: &Self) -> Option<Self> {
+                <$t>::checked_div(*self, *other)
+            }
+
+            fn checked_pow(&self, exp: &Self) -> Option<Self> {
+                <$t>::checked_pow(*self, u32::try_from(*exp).ok()?)
+            }
+
+            fn checked_neg(&self) -> Option<Self> {
+                <$t>::checked_neg(*self)
+            }
+        }
+
+        impl Integer for $t {
+            fn checked_rem(&self, other: &Self) -> Option<Self> {
+                <$t>::checked_rem(*self, *other)
+            }
+        }
+    };
+}
+
+impl_num_for_int!(i64);
+impl_num_for_int!(i128);
+
+impl Num for BigInt {
+    fn from_usize(val: usize) -> Option<Self> {
+        Some(BigInt::from(val))
+    }
+
+    fn checked_add(&self, other: &Self) -> Option<Self> {
+        Some(self + other)
+    }
+
+    fn checked_sub(&self, other: &Self) -> Option<Self> {
+        Some(self - other)
+    }
+
+    fn checked_mul(&self, other: &Self) -> Option<Self> {
+        Some(self * other)
+    }
+
+    fn checked_div(&self, other: &Self) -> Option<Self> {
+        self.div_rem(other).map(|(q, _)| q)
+    }
+
+    fn checked_pow(&self, exp: &Self) -> Option<Self> {
+        let exp = exp.to_i128()?;
+        Some(self.pow(u32::try_from(exp).ok()?))
+    }
+
+    fn checked_neg(&self) -> Option<Self> {
+        Some(-self)
+    }
+}
+
+impl Integer for BigInt {
+    fn checked_rem(&self, other: &Self) -> Option<Self> {
+        self.div_rem(other).map(|(_, r)| r)
+    }
+}